@@ -0,0 +1,36 @@
+use std::path::PathBuf;
+
+// ~/.config/lesser/config.toml, see keymap.rs and theme.rs for what goes in it
+pub fn config_path() -> Option<PathBuf> {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?).join(".config"),
+    };
+    Some(config_dir.join("lesser").join("config.toml"))
+}
+
+// A missing config is the same as an empty one, a broken one is warned about and otherwise treated as empty too
+pub fn load_config() -> toml::Table {
+    let Some(path) = config_path() else {
+        return toml::Table::new();
+    };
+    let Ok(config) = std::fs::read_to_string(&path) else {
+        return toml::Table::new();
+    };
+
+    match config.parse::<toml::Table>() {
+        Ok(table) => table,
+        Err(e) => {
+            warn(&format!("ignoring invalid config: {}", e.message()));
+            toml::Table::new()
+        }
+    }
+}
+
+// Printed before we take over the screen, so they're still there once we quit
+pub fn warn(warning: &str) {
+    match config_path() {
+        Some(path) => eprintln!("{}: {}", path.display(), warning),
+        None => eprintln!("{}", warning),
+    }
+}
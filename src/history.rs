@@ -0,0 +1,60 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+// Where each file was last left, like less's .lesshst, so reopening it picks up in the same place
+// Each line is the 1-based line that was at the top of the screen, a tab, then the file's canonical path
+
+fn history_path() -> Option<PathBuf> {
+    let state_dir = match std::env::var_os("XDG_STATE_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?).join(".local").join("state"),
+    };
+    Some(state_dir.join("lesser").join("history"))
+}
+
+// Lines that can't be parsed are skipped, a broken history shouldn't stop anything from being viewed
+pub fn load_history() -> HashMap<PathBuf, usize> {
+    let Some(contents) = history_path().and_then(|path| std::fs::read_to_string(path).ok()) else {
+        return HashMap::new();
+    };
+
+    contents.lines().filter_map(|line| {
+        let (line_no, path) = line.split_once('\t')?;
+        Some((PathBuf::from(path), line_no.parse().ok()?))
+    }).collect()
+}
+
+// The line to open a file at, if it's been viewed before
+pub fn saved_line_no(history: &HashMap<PathBuf, usize>, path: &Path) -> Option<usize> {
+    history.get(&std::fs::canonicalize(path).ok()?).copied()
+}
+
+// Merged into whatever's already saved, files that have since been deleted are dropped while we're at it
+// Errors are ignored since we're on our way out, and losing a position isn't worth complaining about
+pub fn save_history(positions: &[(&Path, usize)]) {
+    let Some(history_path) = history_path() else {
+        return;
+    };
+
+    let mut history = load_history();
+    for (path, line_no) in positions {
+        if let Ok(path) = std::fs::canonicalize(path) {
+            history.insert(path, *line_no);
+        }
+    }
+    history.retain(|path, _| path.exists());
+
+    let mut contents = String::new();
+    for (path, line_no) in history {
+        // A tab or newline in the path would break the format, and those are rare enough to not bother with
+        let path = path.to_string_lossy();
+        if !path.contains(['\t', '\n']) {
+            contents.push_str(&format!("{}\t{}\n", line_no, path));
+        }
+    }
+
+    if let Some(dir) = history_path.parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    let _ = std::fs::write(history_path, contents);
+}
@@ -1,31 +1,44 @@
 use std::sync::mpsc;
+use std::sync::mpsc::TryRecvError;
 use std::time::Duration;
-use crossterm::event::{poll, read, Event};
-use crate::messaging::{TerminalThreadMessage};
+use crossterm::event::{poll, read, Event, MouseEventKind};
+use crate::messaging::{InputThreadMessage, TerminalThreadMessage};
 
-pub fn input_thread_fn(term_tx: mpsc::Sender<TerminalThreadMessage>) {
+// How far one notch of the mouse wheel scrolls
+const WHEEL_LINES: i32 = 3;
+
+// Keys are read from the terminal rather than from stdin, so this still works when content is piped in
+// crossterm handles that itself: on Unix it opens /dev/tty whenever stdin isn't a tty (and always on macOS,
+// see the use-dev-tty feature in Cargo.toml), and on Windows it reads from the console input buffer
+pub fn input_thread_fn(term_tx: mpsc::Sender<TerminalThreadMessage>, input_rx: mpsc::Receiver<InputThreadMessage>) {
     loop {
+        match input_rx.try_recv() {
+            Ok(InputThreadMessage::Exit) | Err(TryRecvError::Disconnected) => {
+                break;
+            }
+            Ok(InputThreadMessage::Pause(ack)) => {
+                let _ = ack.send(());
+                if !wait_for_resume(&input_rx) {
+                    break;
+                }
+            }
+            Ok(InputThreadMessage::Resume) | Err(TryRecvError::Empty) => {}
+        }
+
         match poll(Duration::from_millis(100)).unwrap() {
             true => {
-                match read().unwrap() {
-                    Event::Key(event) => {
-                        match term_tx.send(TerminalThreadMessage::KeyEvent(event)) {
-                            Err(_) => {
-                                break;
-                            }
-                            _ => {}
-                        }
-                    },
-                    Event::Resize(cols, rows) => {
-                        match term_tx.send(TerminalThreadMessage::Resize(cols, rows)) {
-                            Err(_) => {
-                                break;
-                            }
-                            _ => {}
-                        }
-                    }
+                let message = match read().unwrap() {
+                    Event::Key(event) => TerminalThreadMessage::KeyEvent(event),
+                    Event::Resize(cols, rows) => TerminalThreadMessage::Resize(cols, rows),
+                    Event::Mouse(event) if event.kind == MouseEventKind::ScrollUp => TerminalThreadMessage::Scroll(-WHEEL_LINES),
+                    Event::Mouse(event) if event.kind == MouseEventKind::ScrollDown => TerminalThreadMessage::Scroll(WHEEL_LINES),
                     _ => {
+                        continue;
                     }
+                };
+
+                if term_tx.send(message).is_err() {
+                    break;
                 }
             },
             false => {
@@ -34,3 +47,20 @@ pub fn input_thread_fn(term_tx: mpsc::Sender<TerminalThreadMessage>) {
         }
     }
 }
+
+// Returns false if we should exit instead
+fn wait_for_resume(input_rx: &mpsc::Receiver<InputThreadMessage>) -> bool {
+    loop {
+        match input_rx.recv() {
+            Ok(InputThreadMessage::Resume) => {
+                return true;
+            }
+            Ok(InputThreadMessage::Pause(ack)) => {
+                let _ = ack.send(());
+            }
+            Ok(InputThreadMessage::Exit) | Err(_) => {
+                return false;
+            }
+        }
+    }
+}
@@ -2,108 +2,728 @@ mod terminal;
 mod messaging;
 mod input;
 mod reader;
+mod history;
+mod config;
 
-use std::{fs::File, io::{BufRead, Write}, sync::{mpsc, Arc, Mutex}, thread};
-use std::fs::metadata;
-use std::io::{Read, Seek};
+use std::{fs::File, sync::{atomic::AtomicUsize, mpsc, Arc, Mutex}, thread};
+use std::io::{IsTerminal, Write};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use clap::Parser;
-use notify::{Watcher};
 use crate::input::input_thread_fn;
-use crate::messaging::{TerminalThreadMessage};
-use crate::reader::line_reader::{FileReader, LineReader, StdinReader, WatchingFileReader};
-use crate::reader::reader_thread_fn;
-use crate::terminal::term_thread_fn;
-
-#[cfg(unix)]
-fn get_tty() -> File {
-    File::open("/dev/tty").expect("Could not open /dev/tty")
-}
-
-#[cfg(windows)]
-fn get_tty() -> File {
-    // CON is the equivalent to /dev/tty on Windows
-    File::open("CON").expect("Could not open CON")
-}
+use crate::messaging::{InputThreadMessage, ReaderThreadMessage, TerminalThreadMessage};
+use crate::reader::line_index::LineIndex;
+use crate::reader::line_reader::{CommandReader, FifoReader, FileReader, LineReader, StdinReader, WatchingFileReader};
+use crate::reader::{flush_tee, reader_thread_fn, tee_flush_thread_fn, tee_line, Aggregate, Lines, Tee};
+use crossterm::style::Color;
+use crate::terminal::keymap::keymap_from_config;
+use crate::terminal::theme::{parse_color, theme_from_config};
+use crate::terminal::{dump_screen, term_thread_fn, RenderOptions, SearchOptions, StartPosition, TermOptions};
 
 #[derive(clap::Parser)]
 #[derive(Debug)]
 struct Args {
+    /// Files or globs to open, `+N` opens the first one at line N and `+/pattern` at the first match of pattern
     filenames: Option<Vec<String>>,
 
     #[arg(long)]
     watch: bool,
+
+    /// Run a shell command and page its output as it's written, alongside any files
+    #[arg(short = 'c', long)]
+    command: Option<String>,
+
+    /// Re-run the command every this many seconds and show the latest output, like watch
+    #[arg(long, requires = "command")]
+    interval: Option<f64>,
+
+    /// How often to check watched files for changes, in milliseconds, if they can't be watched natively
+    #[arg(long, default_value_t = 500)]
+    poll_interval: u64,
+
+    /// Show line numbers in a gutter on the left, can be toggled with `l`
+    #[arg(short = 'N', long)]
+    line_numbers: bool,
+
+    /// Strip colour escape sequences from the input instead of rendering them
+    #[arg(short = 'r', long)]
+    no_color: bool,
+
+    /// Whether the status bar and search highlights use colour, auto turns it off if NO_COLOR is set
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+
+    /// Background colour of the current search match, as a name, a 256 colour number or #rrggbb
+    #[arg(long, value_parser = parse_color)]
+    highlight_bg: Option<Color>,
+
+    /// Text colour of the current search match
+    #[arg(long, value_parser = parse_color)]
+    highlight_fg: Option<Color>,
+
+    /// Background colour of the other search matches
+    #[arg(long, value_parser = parse_color)]
+    match_bg: Option<Color>,
+
+    /// Text colour of the other search matches
+    #[arg(long, value_parser = parse_color)]
+    match_fg: Option<Color>,
+
+    /// Background colour of the status bar
+    #[arg(long, value_parser = parse_color)]
+    status_bg: Option<Color>,
+
+    /// Text colour of the status bar
+    #[arg(long, value_parser = parse_color)]
+    status_fg: Option<Color>,
+
+    /// Make plain searches case sensitive, can be toggled with Ctrl-S while typing a search
+    #[arg(short = 'I', long)]
+    case_sensitive: bool,
+
+    /// Make plain searches only match whole words, can be toggled with Ctrl-B while typing a search
+    #[arg(short = 'w', long)]
+    whole_word: bool,
+
+    /// Make plain searches case sensitive only if they have an uppercase letter in them, until Ctrl-S is pressed
+    #[arg(long, conflicts_with = "case_sensitive")]
+    smartcase: bool,
+
+    /// Just print the input and exit if it fits on one screen
+    #[arg(short = 'F', long)]
+    quit_if_one_screen: bool,
+
+    /// Page even if stdout isn't a terminal, otherwise the input is just copied to stdout like cat
+    #[arg(long)]
+    force: bool,
+
+    /// Cut long lines off at the edge of the screen instead of wrapping them, can be toggled with `x`
+    #[arg(short = 'S', long)]
+    chop_long_lines: bool,
+
+    /// Wrap long lines at word boundaries where possible, can be toggled with `w`
+    #[arg(short = 'W', long)]
+    word_wrap: bool,
+
+    /// Show runs of blank lines as a single blank line, line numbers and searches still count every line
+    #[arg(short = 's', long)]
+    squeeze_blank: bool,
+
+    /// How many lines d, u, PgDn and PgUp scroll by, instead of half a screen
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    scroll: Option<u64>,
+
+    /// Keep at most this many lines of each input in memory, dropping the oldest, e.g. for a command that never ends
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_lines: Option<u64>,
+
+    /// Put search matches and lines jumped to this many rows from the top of the screen, instead of in the middle
+    #[arg(long)]
+    jump_target: Option<usize>,
+
+    /// Keep at least this many lines above and below a search match or line that's jumped to, like vim's scrolloff
+    #[arg(long, default_value_t = 0)]
+    scrolloff: usize,
+
+    /// Keep the first N lines pinned to the top of the screen, e.g. a CSV header, can be changed with `t`
+    #[arg(long, default_value_t = 0)]
+    header: usize,
+
+    /// Print the first screen to stdout and exit instead of paging, e.g. for snapshots, with `+N` to pick the position
+    #[arg(long)]
+    dump: bool,
+
+    /// Height of the screen for --dump, defaults to the terminal's
+    #[arg(long, requires = "dump", value_parser = clap::value_parser!(u16).range(2..))]
+    rows: Option<u16>,
+
+    /// Width of the screen for --dump, defaults to the terminal's
+    #[arg(long, requires = "dump", value_parser = clap::value_parser!(u16).range(1..))]
+    cols: Option<u16>,
+
+    /// In the view of all files together, start each line with the name of the file it came from
+    #[arg(long)]
+    prefix_source: bool,
+
+    /// In the view of all files together, show a line that's the same as the one before it as a count on that one
+    #[arg(long)]
+    uniq: bool,
+
+    /// Save a copy of everything read to this file, like tee
+    #[arg(long, conflicts_with = "dump")]
+    output: Option<String>,
+
+    /// A regex for the lines that start a section, e.g. ^# for markdown headings, ]] and [[ go to the next/previous one
+    #[arg(long, value_parser = regex::Regex::new)]
+    section: Option<regex::Regex>,
+
+    /// Open every file under any directories given, skipping ones that look binary
+    #[arg(short = 'R', long)]
+    recursive: bool,
+
+    /// Print control characters as they are instead of as ^A, ^G and so on
+    #[arg(long)]
+    raw_control_chars: bool,
+
+    /// Show man page style overstruck text (X, backspace, X) as it is instead of in bold and underlined
+    #[arg(long)]
+    no_overstrike: bool,
+
+    /// Show spaces as · and tabs as →, with whitespace at the ends of lines in reverse video. Toggled with i
+    #[arg(long)]
+    show_whitespace: bool,
+
+    /// Don't scroll with the mouse wheel, so the terminal can select text as usual, can be toggled with `z`
+    #[arg(long)]
+    no_mouse: bool,
+
+    /// Don't open files where they were left last time, or remember where they're left this time
+    #[arg(long)]
+    no_history: bool,
+
+    /// Width of tab stops
+    #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(u16).range(1..))]
+    tabs: u16,
+
+    /// Encoding of the input files, by default UTF-16 and UTF-8 files with a byte order mark are decoded and
+    /// anything else is read as UTF-8. Doesn't apply with --watch
+    #[arg(long, value_enum)]
+    encoding: Option<InputEncoding>,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum InputEncoding {
+    Utf8,
+    Utf16le,
+    Utf16be,
+    // Read as Windows-1252, which is what Latin-1 almost always turns out to mean
+    Latin1,
+}
+
+impl InputEncoding {
+    fn encoding(self) -> &'static encoding_rs::Encoding {
+        match self {
+            InputEncoding::Utf8 => encoding_rs::UTF_8,
+            InputEncoding::Utf16le => encoding_rs::UTF_16LE,
+            InputEncoding::Utf16be => encoding_rs::UTF_16BE,
+            InputEncoding::Latin1 => encoding_rs::WINDOWS_1252,
+        }
+    }
 }
 
 pub struct Source {
     name: String,
-    reader: Mutex<Box<dyn LineReader>>,
-    lines: Mutex<Vec<String>>,
+    // Only set for sources backed by a real file, e.g. so it can be opened in an editor
+    path: Option<PathBuf>,
+    // None for the aggregate of all files, whose lines are filled in by the other sources' readers
+    reader: Option<Mutex<Box<dyn LineReader>>>,
+    lines: Mutex<Lines>,
+    // Checked once up front, so we can ask before showing it
+    binary: bool,
+    // Read with --watch (or a named pipe), so it being empty means nothing's been written to it yet rather than that
+    // it's empty
+    watched: bool,
+    // Before any decoding, for showing with `=` when there's no file to get the size of
+    bytes_read: AtomicUsize,
+    // How many lines have been dropped from the start of lines by --max-lines, only changed with lines locked
+    dropped: AtomicUsize,
 }
 
-fn main() {
-    let args = Args::parse();
+// Files at least this big are indexed rather than read into memory, see Lines
+const INDEX_MIN_BYTES: u64 = 64 * 1024 * 1024;
 
-    let mut sources: Vec<Arc<Source>> = match &args.filenames {
-        Some(filenames) => {
-            filenames.iter().flat_map(|pattern| glob::glob(pattern).expect("Could not create glob").into_iter()).map(|path| {
-                let fname = path.expect("Could not read globbed path").to_string_lossy().to_string();
-                let file = File::open(fname.as_str()).expect("Could not open input file");
-                if file.metadata().expect("Could not read metadata").is_dir() {
-                    return None;
+#[cfg(unix)]
+fn is_fifo(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    metadata.file_type().is_fifo()
+}
+
+// Windows named pipes don't live in the filesystem like this
+#[cfg(not(unix))]
+fn is_fifo(_metadata: &std::fs::Metadata) -> bool {
+    false
+}
+
+// Where a source's lines come from, before it's opened
+#[derive(Debug, PartialEq)]
+enum Input {
+    Stdin,
+    File(PathBuf),
+    // Found by --recursive rather than named on the command line
+    WalkedFile(PathBuf),
+}
+
+// Limits on --recursive, so pointing it at / by mistake doesn't open a thread for every file on the machine
+const MAX_WALKED_FILES: usize = 1000;
+const MAX_WALK_DEPTH: usize = 32;
+
+// Every regular file under a directory for --recursive, in name order, up to MAX_WALKED_FILES of them
+// Symlinks aren't followed, so a link back up the tree can't send it round in circles
+fn walk_dir(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for entry in walkdir::WalkDir::new(dir).max_depth(MAX_WALK_DEPTH).sort_by_file_name() {
+        match entry {
+            Ok(entry) if entry.file_type().is_file() => {
+                if files.len() == MAX_WALKED_FILES {
+                    eprintln!("Only opening the first {} files in {}", MAX_WALKED_FILES, dir.display());
+                    break;
                 }
+                files.push(entry.into_path());
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Skipping {}", e),
+        }
+    }
+    files
+}
+
+// The inputs a filename argument stands for: stdin for -, otherwise whatever its glob matches, with directories walked
+// for --recursive. A bad pattern is warned about and left out, and one that matches nothing is kept as it is so that
+// opening it gives the usual "No such file" warning
+fn expand_filename(pattern: &str, recursive: bool) -> Vec<Input> {
+    // - stands for stdin, wherever it is in the list
+    if pattern == "-" {
+        return vec![Input::Stdin];
+    }
+
+    let paths = match glob::glob(pattern) {
+        Ok(paths) => paths,
+        Err(e) => {
+            eprintln!("Skipping {}: {}", pattern, e);
+            return Vec::new();
+        }
+    };
+    let paths = paths.filter_map(|path| match path {
+        Ok(path) => Some(path),
+        Err(e) => {
+            eprintln!("Skipping {}: {}", e.path().display(), e.error());
+            None
+        }
+    }).collect::<Vec<PathBuf>>();
+    let paths = if paths.is_empty() { vec![PathBuf::from(pattern)] } else { paths };
 
-                if !args.watch {
-                    Some(
-                            Arc::new(
-                            Source {
-                                reader: Mutex::new(Box::new(FileReader::new(file)) as Box<dyn LineReader>),
-                                name: fname,
-                                lines: Mutex::new(Vec::<String>::new()),
-                            }
-                        )
-                    )
-                } else {
-                    Some(
-                            Arc::new(
-                            Source {
-                                reader: Mutex::new(Box::new(WatchingFileReader::new(file, fname.as_str())) as Box<dyn LineReader>),
-                                name: fname,
-                                lines: Mutex::new(Vec::<String>::new()),
-                            }
-                        )
-                    )
+    paths.into_iter().flat_map(|path| {
+        if recursive && path.is_dir() {
+            walk_dir(&path).into_iter().map(Input::WalkedFile).collect()
+        } else {
+            vec![Input::File(path)]
+        }
+    }).collect()
+}
+
+// Opens a source for an input. Anything that can't be read (e.g. a broken symlink or a file we don't have permission
+// for) is warned about and skipped rather than taking the rest down with it
+fn open_input(input: Input, args: &Args) -> Option<Arc<Source>> {
+    let (path, walked) = match input {
+        Input::Stdin => return Some(stdin_source()),
+        Input::File(path) => (path, false),
+        Input::WalkedFile(path) => (path, true),
+    };
+    let fname = path.to_string_lossy().to_string();
+    // Checked before opening, since opening a named pipe waits for something to write to it
+    let metadata = match std::fs::metadata(fname.as_str()) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            eprintln!("Skipping {}: {}", fname, e);
+            return None;
+        }
+    };
+    if metadata.is_dir() {
+        eprintln!("Skipping {}: is a directory (use --recursive)", fname);
+        return None;
+    }
+
+    // --dump reads to the end of the input, which never comes for watched files
+    let watch = args.watch && !args.dump;
+    let fifo = is_fifo(&metadata);
+    let mut reader = if fifo {
+        Box::new(FifoReader::new(fname.as_str(), watch)) as Box<dyn LineReader>
+    } else {
+        let file = match File::open(fname.as_str()) {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!("Skipping {}: {}", fname, e);
+                return None;
+            }
+        };
+        if !watch {
+            Box::new(FileReader::new(file, args.encoding.map(InputEncoding::encoding))) as Box<dyn LineReader>
+        } else {
+            match WatchingFileReader::new(file, fname.as_str(), Duration::from_millis(args.poll_interval)) {
+                Ok(reader) => Box::new(reader) as Box<dyn LineReader>,
+                Err(e) => {
+                    eprintln!("Skipping {}: {}", fname, e);
+                    return None;
                 }
-            }).filter(|s| s.is_some()).map(|s| s.unwrap()).collect()
+            }
+        }
+    };
+    let binary = reader.looks_binary();
+    // There's no asking about every binary file in a directory tree, they're just left out
+    if binary && walked {
+        return None;
+    }
+    // A pipe can't be peeked at without waiting for a writer, so it's empty until one comes along
+    let watched = watch || fifo;
+    let index = if !watched && args.max_lines.is_none() && matches!(args.encoding, None | Some(InputEncoding::Utf8)) && metadata.len() >= INDEX_MIN_BYTES { LineIndex::open(Path::new(&fname)) } else { None };
+    Some(
+        Arc::new(
+            Source {
+                reader: Some(Mutex::new(reader)),
+                path: Some(PathBuf::from(&fname)),
+                name: fname,
+                lines: Mutex::new(index.map_or(Lines::Loaded(Vec::new()), Lines::Indexed)),
+                binary,
+                watched,
+                bytes_read: AtomicUsize::new(0),
+                dropped: AtomicUsize::new(0),
+            }
+        )
+    )
+}
+
+fn stdin_source() -> Arc<Source> {
+    Arc::new(
+        Source {
+            name: "stdin".to_string(),
+            path: None,
+            reader: Some(Mutex::new(Box::new(StdinReader::new()))),
+            lines: Mutex::new(Lines::Loaded(Vec::new())),
+            binary: false,
+            watched: false,
+            bytes_read: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }
+    )
+}
+
+// Pulls less-style `+N` and `+/pattern` arguments out from amongst the filenames, the last one wins
+fn split_start_position(args: &[String]) -> (Option<StartPosition>, Vec<String>) {
+    let mut start = None;
+    let mut filenames = Vec::new();
+    for arg in args {
+        if let Some(pattern) = arg.strip_prefix("+/") {
+            start = Some(StartPosition::Pattern(pattern.to_string()));
+        } else if let Some(line_no) = arg.strip_prefix('+').and_then(|n| n.parse::<usize>().ok()) {
+            start = Some(StartPosition::Line(line_no));
+        } else {
+            filenames.push(arg.clone());
+        }
+    }
+    (start, filenames)
+}
+
+// Used when stdout isn't a terminal, e.g. `lesser file | grep foo`, where paging would just corrupt the pipe
+// With --output everything's copied there too, as it would be while paging
+fn cat_sources(sources: &[Arc<Source>], tee: Option<&Mutex<Tee>>) {
+    let mut output = std::io::stdout().lock();
+    let mut buf = Vec::<u8>::new();
+    for source in sources {
+        let Some(reader) = &source.reader else {
+            continue;
+        };
+        let mut reader = reader.lock().expect("Could not take lock in cat_sources");
+        while let Ok(n) = reader.read_line(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            if let Some(Err(message)) = tee.map(|tee| tee_line(tee, &buf)) {
+                eprintln!("{}", message);
+            }
+            // Stop quietly if the other end of the pipe goes away
+            if output.write_all(&buf).is_err() {
+                break;
+            }
+            buf.clear();
+        }
+    }
+    let _ = output.flush();
+    if let Some(Err(message)) = tee.map(flush_tee) {
+        eprintln!("{}", message);
+    }
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let (mut start, filenames) = split_start_position(args.filenames.as_deref().unwrap_or_default());
+    let filenames = if filenames.is_empty() { None } else { Some(filenames) };
+
+    let mut sources: Vec<Arc<Source>> = match &filenames {
+        None if args.command.is_some() => {
+            Vec::new()
+        }
+        Some(filenames) => {
+            filenames.iter().flat_map(|pattern| expand_filename(pattern, args.recursive)).filter_map(|input| open_input(input, &args)).collect()
         }
         None => {
-            vec!(
-                Arc::new(
-                    Source {
-                        name: "stdin".to_string(),
-                        reader: Mutex::new(Box::new(StdinReader::new())),
-                        lines: Mutex::new(Vec::<String>::new()),
-                    }
-                )
-            )
+            vec!(stdin_source())
         }
     };
 
-    if sources.len() == 0 {
+    if let Some(command) = &args.command {
+        match CommandReader::new(command, args.interval.map(Duration::from_secs_f64)) {
+            Ok(reader) => {
+                sources.push(Arc::new(Source {
+                    name: command.clone(),
+                    path: None,
+                    reader: Some(Mutex::new(Box::new(reader))),
+                    lines: Mutex::new(Lines::Loaded(Vec::new())),
+                    binary: false,
+                    watched: false,
+                    bytes_read: AtomicUsize::new(0),
+                    dropped: AtomicUsize::new(0),
+                }));
+            }
+            Err(e) => {
+                eprintln!("Could not run command: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if sources.is_empty() {
         eprintln!("No valid input sources");
         std::process::exit(1);
     }
 
-    let (term_tx, term_rx) = mpsc::channel::<TerminalThreadMessage>();
+    let config = config::load_config();
+    let mut theme = theme_from_config(&config);
+    theme.highlight_bg = args.highlight_bg.unwrap_or(theme.highlight_bg);
+    theme.highlight_fg = args.highlight_fg.unwrap_or(theme.highlight_fg);
+    theme.match_bg = args.match_bg.unwrap_or(theme.match_bg);
+    theme.match_fg = args.match_fg.unwrap_or(theme.match_fg);
+    theme.status_bg = args.status_bg.unwrap_or(theme.status_bg);
+    theme.status_fg = args.status_fg.unwrap_or(theme.status_fg);
+
+    let render_options = RenderOptions {
+        line_numbers: args.line_numbers,
+        color: !args.no_color,
+        tab_width: args.tabs as usize,
+        chop_long_lines: args.chop_long_lines,
+        word_wrap: args.word_wrap,
+        squeeze_blank: args.squeeze_blank,
+        raw_control_chars: args.raw_control_chars,
+        overstrike: !args.no_overstrike,
+        show_whitespace: args.show_whitespace,
+        scroll: args.scroll.map(|n| n as usize),
+        jump_target: args.jump_target,
+        scrolloff: args.scrolloff,
+        header: args.header,
+        line_nos: None,
+        dropped_lines: 0,
+        top_segment: (0, 0),
+        // Not being attached to a terminal is the point of --dump, so it falls back to a typical size. Paging asks the
+        // terminal again once it starts
+        size: crossterm::terminal::size().unwrap_or((80, 24)),
+        ui_color: match args.color {
+            // See no-color.org, a dumb terminal can't do colour either
+            ColorChoice::Auto => std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty()) && std::env::var("TERM").map_or(true, |term| term != "dumb"),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        },
+        theme,
+    };
+
+    let search_options = SearchOptions {
+        case_sensitive: args.case_sensitive,
+        whole_word: args.whole_word,
+        smartcase: args.smartcase,
+        history: Vec::new(),
+    };
+
+    if args.dump {
+        let (term_cols, term_rows) = render_options.size;
+        reader::read_to_end(&sources[0]);
+        dump_screen(&sources[0], RenderOptions { size: (args.cols.unwrap_or(term_cols), args.rows.unwrap_or(term_rows)), ..render_options }, search_options, start);
+        return;
+    }
 
-    let term_tx2 = term_tx.clone();
-    thread::scope(|scope| {
-        for source in sources.iter() {
-            let source = source.clone();
-            let term_tx = term_tx.clone();
-            scope.spawn(move|| reader_thread_fn(source, term_tx));
+    let tee = args.output.as_ref().map(|path| match File::create(path) {
+        Ok(file) => Arc::new(Mutex::new(Tee::new(path, file))),
+        Err(e) => {
+            eprintln!("Could not open output file: {}", e);
+            std::process::exit(1);
         }
+    });
+
+    if !args.force && !std::io::stdout().is_terminal() {
+        cat_sources(&sources, tee.as_deref());
+        return;
+    }
 
-        scope.spawn(move|| term_thread_fn(&sources, term_rx));
-        scope.spawn(move|| input_thread_fn(term_tx2));
+    let history = if args.no_history { HashMap::new() } else { history::load_history() };
+    let saved_line_nos = sources.iter().map(|source| source.path.as_deref().and_then(|path| history::saved_line_no(&history, path))).collect::<Vec<_>>();
+    // An explicit +N or +/pattern wins over where the file was left, and files left at the top start as usual
+    if start.is_none() {
+        start = saved_line_nos[0].filter(|&line_no| line_no > 1).map(StartPosition::Line);
+    }
+
+    let term_options = TermOptions {
+        quit_if_one_screen: args.quit_if_one_screen,
+        mouse: !args.no_mouse,
+        keymap: keymap_from_config(&config),
+        saved_line_nos,
+        section: args.section,
+    };
+
+    let (term_tx, term_rx) = mpsc::channel::<TerminalThreadMessage>();
+
+    let (input_tx, input_rx) = mpsc::channel::<InputThreadMessage>();
+    let input_tx2 = input_tx.clone();
+
+    // Readers aren't part of the scope since they may be blocked reading forever (e.g. on stdin)
+    // They'll be torn down when main returns
+    // With several files there's also a view of all of them together, in the order their lines were read
+    let aggregate = if sources.len() > 1 {
+        let source = Arc::new(Source {
+            name: "all files".to_string(),
+            path: None,
+            reader: None,
+            lines: Mutex::new(Lines::Loaded(Vec::new())),
+            binary: false,
+            watched: false,
+            bytes_read: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        });
+        sources.push(source.clone());
+        Some(Aggregate { source, index: sources.len() - 1, prefix_source: args.prefix_source, uniq: args.uniq, last_line: Arc::new(Mutex::new(None)) })
+    } else {
+        None
+    };
+
+    let max_lines = args.max_lines.map(|n| n as usize);
+    if let Some(tee) = &tee {
+        let tee = tee.clone();
+        let term_tx = term_tx.clone();
+        thread::spawn(move|| tee_flush_thread_fn(tee, term_tx));
+    }
+    let reader_txs = sources.iter().enumerate().filter(|(_, source)| source.reader.is_some()).map(|(source_index, source)| {
+        let source = source.clone();
+        let aggregate = aggregate.clone();
+        let tee = tee.clone();
+        let term_tx = term_tx.clone();
+        let (reader_tx, reader_rx) = mpsc::channel::<ReaderThreadMessage>();
+        thread::spawn(move|| reader_thread_fn(source, source_index, aggregate, max_lines, tee, term_tx, reader_rx));
+        reader_tx
+    }).collect::<Vec<_>>();
+
+    let term_thread_result = thread::scope(|scope| {
+        scope.spawn(move|| input_thread_fn(term_tx, input_rx));
+        let term_thread = scope.spawn(|| term_thread_fn(&sources, term_rx, input_tx2, render_options, search_options, start, term_options));
+
+        // term_thread_fn only returns once the user quits
+        let result = term_thread.join();
+        let _ = input_tx.send(InputThreadMessage::Exit);
+        for reader_tx in reader_txs.iter() {
+            let _ = reader_tx.send(ReaderThreadMessage::Exit);
+        }
+        result
     });
+
+    // Readers that are still going won't get the chance to flush what they've written
+    if let Some(Err(message)) = tee.as_ref().map(|tee| flush_tee(tee)) {
+        eprintln!("{}", message);
+    }
+
+    match term_thread_result {
+        Ok(line_nos) => {
+            if !args.no_history {
+                let positions = sources.iter().zip(line_nos).filter_map(|(source, line_no)| Some((source.path.as_deref()?, line_no))).collect::<Vec<_>>();
+                history::save_history(&positions);
+            }
+        }
+        Err(_) => {
+            // The panic hook has already put the terminal back and printed the panic
+            std::process::exit(101);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A fresh directory under the system temp dir for a test to make files in
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lesser-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).expect("Could not create test dir");
+        dir
+    }
+
+    #[test]
+    fn dash_is_stdin() {
+        assert_eq!(expand_filename("-", false), vec![Input::Stdin]);
+    }
+
+    #[test]
+    fn glob_matches_in_name_order() {
+        let dir = test_dir("glob");
+        for name in ["b.log", "a.log", "c.txt"] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        let pattern = dir.join("*.log").to_string_lossy().to_string();
+        assert_eq!(expand_filename(&pattern, false), vec![Input::File(dir.join("a.log")), Input::File(dir.join("b.log"))]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn bad_glob_is_skipped() {
+        assert_eq!(expand_filename("/tmp/foo[.log", false), Vec::new());
+    }
+
+    #[test]
+    fn missing_file_is_kept_to_be_reported() {
+        let dir = test_dir("missing");
+        let path = dir.join("nope.txt");
+        assert_eq!(expand_filename(&path.to_string_lossy(), false), vec![Input::File(path)]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn directories_are_only_walked_with_recursive() {
+        let dir = test_dir("walk");
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("sub").join("b.txt"), "").unwrap();
+        std::fs::write(dir.join("a.txt"), "").unwrap();
+        let pattern = dir.to_string_lossy().to_string();
+        assert_eq!(expand_filename(&pattern, false), vec![Input::File(dir.clone())]);
+        assert_eq!(expand_filename(&pattern, true), vec![Input::WalkedFile(dir.join("a.txt")), Input::WalkedFile(dir.join("sub").join("b.txt"))]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unreadable_files_in_a_glob_are_skipped() {
+        let dir = test_dir("unreadable");
+        std::fs::write(dir.join("a.log"), "a\n").unwrap();
+        std::os::unix::fs::symlink(dir.join("gone"), dir.join("b.log")).unwrap();
+        std::fs::write(dir.join("c.log"), "c\n").unwrap();
+
+        let args = Args::parse_from(["lesser"]);
+        let pattern = dir.join("*.log").to_string_lossy().to_string();
+        let sources = expand_filename(&pattern, false).into_iter().filter_map(|input| open_input(input, &args)).collect::<Vec<_>>();
+        let names = sources.iter().map(|source| source.path.clone()).collect::<Vec<_>>();
+        assert_eq!(names, vec![Some(dir.join("a.log")), Some(dir.join("c.log"))]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn missing_file_named_directly_is_skipped() {
+        let dir = test_dir("skip-missing");
+        std::fs::write(dir.join("there.txt"), "a\n").unwrap();
+        let args = Args::parse_from(["lesser"]);
+        assert!(open_input(Input::File(dir.join("nope.txt")), &args).is_none());
+        assert!(open_input(Input::File(dir.join("there.txt")), &args).is_some());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -1,6 +1,29 @@
 
 pub enum TerminalThreadMessage {
     KeyEvent(crossterm::event::KeyEvent),
+    // Lines to scroll by from the mouse wheel, negative is up
+    Scroll(i32),
+    // (cols, rows)
     Resize(u16, u16),
-    Read
+    // New lines were read into the source at this index
+    Read(usize),
+    // The source at this index has been read to the end, watched files and some streams never get here
+    Eof(usize),
+    // The source's old lines were thrown away and it's being read again from the start, e.g. a watched file was
+    // truncated. new_len is how many lines it'll have if the reader knows
+    Reloaded { source_index: usize, old_len: usize, new_len: Option<usize>, message: String },
+    // Something worth telling the user about that happened outside of the terminal thread
+    Status(String),
+}
+
+pub enum InputThreadMessage {
+    Exit,
+    // Stop reading keys until Resume, e.g. while an editor has the terminal
+    // The sender is used to acknowledge once we've stopped
+    Pause(std::sync::mpsc::Sender<()>),
+    Resume,
+}
+
+pub enum ReaderThreadMessage {
+    Exit
 }
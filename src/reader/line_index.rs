@@ -0,0 +1,134 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use super::decode_line;
+use super::line_reader::GZIP_MAGIC;
+
+// Byte order marks, which FileReader strips (and decodes for anything but UTF-8) before splitting lines
+const BOMS: [&[u8]; 3] = [b"\xef\xbb\xbf", b"\xff\xfe", b"\xfe\xff"];
+
+// For big files, rather than keeping every line in memory we keep where each one starts in the file, and read the
+// ones that are needed (mostly the ones on screen) back from it with a seek and a read
+// A clone shares the file and offsets, but only has the lines there were when it was made, e.g. a search's snapshot
+#[derive(Clone)]
+pub struct LineIndex {
+    // Behind a lock so lines can be read back while the source's lines are only borrowed, e.g. while drawing
+    file: Arc<Mutex<IndexedFile>>,
+    len: usize,
+}
+
+struct IndexedFile {
+    // A handle of our own, separate from the reader's, so reading lines back doesn't move where that's reading from
+    reader: BufReader<File>,
+    // Where reader is in the file, so reading lines in order (e.g. a search) doesn't need a seek for each one
+    position: u64,
+    // Where each line starts, and then where the last one ends
+    offsets: Vec<u64>,
+}
+
+impl LineIndex {
+    // The offsets are of the file as it is on disk, so a file that's decompressed or decoded before it's split into
+    // lines (i.e. gzipped or starting with a byte order mark) can't be indexed and this gives None
+    pub fn open(path: &Path) -> Option<Self> {
+        let mut reader = BufReader::new(File::open(path).ok()?);
+        let start = reader.fill_buf().ok()?;
+        if start.starts_with(&GZIP_MAGIC) || BOMS.iter().any(|bom| start.starts_with(bom)) {
+            return None;
+        }
+        Some(LineIndex {
+            file: Arc::new(Mutex::new(IndexedFile { reader, position: 0, offsets: vec![0] })),
+            len: 0,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    // Records the next line the reader got, n bytes of it with its line ending
+    pub fn add_line(&mut self, n: usize) {
+        let mut file = self.file.lock().expect("Could not take lock in add_line");
+        let end = file.offsets[self.len] + n as u64;
+        file.offsets.push(end);
+        self.len += 1;
+    }
+
+    // A line as the reader would have stored it. If the file can't be read any more (e.g. it's been truncated since
+    // it was indexed) the line comes back empty rather than taking the pager down
+    pub fn line(&self, line_no: usize) -> String {
+        let mut file = self.file.lock().expect("Could not take lock in line");
+        let offset = file.offsets[line_no];
+        file.read_line(offset).unwrap_or_default()
+    }
+}
+
+impl IndexedFile {
+    fn read_line(&mut self, offset: u64) -> std::io::Result<String> {
+        if offset != self.position {
+            // Until the seek and read have worked we don't know where we are
+            self.position = u64::MAX;
+            self.reader.seek(SeekFrom::Start(offset))?;
+        }
+        let mut buf = Vec::new();
+        let n = self.reader.read_until(b'\n', &mut buf)?;
+        self.position = offset + n as u64;
+        Ok(decode_line(&buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Indexes a file with the given contents the way reader_thread_fn does, a line at a time
+    fn index_of(name: &str, contents: &[u8]) -> (LineIndex, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!("lesser-test-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).expect("Could not write test file");
+        let mut index = LineIndex::open(&path).expect("Could not index test file");
+        let mut reader = BufReader::new(File::open(&path).unwrap());
+        let mut buf = Vec::new();
+        while reader.read_until(b'\n', &mut buf).unwrap() > 0 {
+            index.add_line(buf.len());
+            buf.clear();
+        }
+        (index, path)
+    }
+
+    #[test]
+    fn reads_lines_back_in_any_order() {
+        let (index, path) = index_of("index-order", b"one\r\ntwo\n\nfour");
+        assert_eq!(index.len(), 4);
+        assert_eq!(index.line(3), "four");
+        assert_eq!(index.line(0), "one");
+        assert_eq!(index.line(2), "");
+        assert_eq!(index.line(1), "two");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn a_clone_keeps_the_lines_it_was_made_with() {
+        let path = std::env::temp_dir().join(format!("lesser-test-{}-index-clone", std::process::id()));
+        std::fs::write(&path, "one\ntwo\n").unwrap();
+        let mut index = LineIndex::open(&path).unwrap();
+        index.add_line(4);
+        let snapshot = index.clone();
+        index.add_line(4);
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(index.len(), 2);
+        // The file is still shared, so both can read lines back
+        assert_eq!(snapshot.line(0), "one");
+        assert_eq!(index.line(1), "two");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn files_that_are_decoded_are_not_indexed() {
+        let path = std::env::temp_dir().join(format!("lesser-test-{}-index-bom", std::process::id()));
+        std::fs::write(&path, b"\xff\xfeh\0i\0").unwrap();
+        assert!(LineIndex::open(&path).is_none());
+        std::fs::write(&path, GZIP_MAGIC).unwrap();
+        assert!(LineIndex::open(&path).is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+}
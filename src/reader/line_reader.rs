@@ -1,13 +1,43 @@
+use std::collections::VecDeque;
 use std::fs::File;
 use std::io::{BufRead, BufReader, Seek};
+use flate2::bufread::MultiGzDecoder;
+use encoding_rs_io::DecodeReaderBytesBuilder;
 use std::path::Path;
 use std::sync::mpsc;
-use std::thread;
 use std::time::Duration;
 use notify::{Config, RecursiveMode, Watcher};
 
+// Lines are read as raw bytes so that invalid UTF-8 doesn't stop us reading, see reader_thread_fn
 pub trait LineReader: Send {
-    fn read_line(&mut self, buf: &mut String) -> std::io::Result<usize>;
+    fn read_line(&mut self, buf: &mut Vec<u8>) -> std::io::Result<usize>;
+
+    // True (once) if the input was truncated since the last call, in which case the line just read is the
+    // first one of the new contents and everything read before it is stale. If the new contents are empty
+    // read_line returns 0 with this set, rather than meaning the end of the input
+    fn take_truncated(&mut self) -> bool {
+        false
+    }
+
+    // What to tell the user after take_truncated
+    fn reload_message(&self) -> String {
+        "file truncated, reloading".to_string()
+    }
+
+    // How many lines the new contents have after take_truncated, if that's known up front
+    fn reload_len(&self) -> Option<usize> {
+        None
+    }
+
+    // Peeks at the start of the input (without using it up) to guess whether it's a binary file rather than text
+    fn looks_binary(&mut self) -> bool {
+        false
+    }
+
+    // Something to tell the user once the input has ended, e.g. how a command exited
+    fn end_message(&mut self) -> Option<String> {
+        None
+    }
 }
 
 pub struct StdinReader {
@@ -23,18 +53,170 @@ impl StdinReader {
 }
 
 impl LineReader for StdinReader {
-    fn read_line(&mut self, buf: &mut String) -> std::io::Result<usize> {
-        self.stdin.read_line(buf)
+    fn read_line(&mut self, buf: &mut Vec<u8>) -> std::io::Result<usize> {
+        self.stdin.lock().read_until(b'\n', buf)
     }
 }
 
+// Runs a shell command and reads its stdout as it's written, so `-f` style commands can be followed
+// The command isn't killed when we quit, but it'll get SIGPIPE as soon as it next writes
+pub struct CommandReader {
+    command: String,
+    // Set with --interval, the command is re-run this long after each run finishes, like watch
+    interval: Option<Duration>,
+    child: Option<std::process::Child>,
+    stdout: Option<BufReader<std::process::ChildStdout>>,
+    // With an interval each run is read in full before any of it is handed out, so we know how long it is
+    pending: VecDeque<Vec<u8>>,
+    runs: usize,
+    truncated: bool,
+    reload_len: Option<usize>,
+}
+
+impl CommandReader {
+    pub fn new(command: &str, interval: Option<Duration>) -> std::io::Result<Self> {
+        let mut reader = CommandReader {
+            command: command.to_string(),
+            interval,
+            child: None,
+            stdout: None,
+            pending: VecDeque::new(),
+            runs: 0,
+            truncated: false,
+            reload_len: None,
+        };
+
+        // Spawned up front so that a command that can't be run is reported straight away
+        let mut child = reader.spawn()?;
+        reader.stdout = Some(BufReader::new(child.stdout.take().expect("Could not get command stdout")));
+        reader.child = Some(child);
+        Ok(reader)
+    }
+
+    fn spawn(&self) -> std::io::Result<std::process::Child> {
+        let (shell, flag) = if cfg!(windows) { ("cmd", "/C") } else { ("sh", "-c") };
+        std::process::Command::new(shell)
+            .arg(flag)
+            .arg(&self.command)
+            // Anything on stderr or reading from stdin would fight with us for the terminal
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::null())
+            .spawn()
+    }
+
+    // Reads the whole of the current run into pending, then starts the next one
+    fn read_run(&mut self, interval: Duration) -> std::io::Result<()> {
+        if let Some(mut stdout) = self.stdout.take() {
+            let mut lines = VecDeque::new();
+            loop {
+                let mut line = Vec::new();
+                if stdout.read_until(b'\n', &mut line)? == 0 {
+                    break;
+                }
+                lines.push_back(line);
+            }
+            if let Some(mut child) = self.child.take() {
+                let _ = child.wait();
+            }
+
+            // The first run is just read, every one after it replaces what came before
+            self.truncated = self.runs > 0;
+            self.reload_len = Some(lines.len());
+            self.runs += 1;
+            self.pending = lines;
+        } else {
+            std::thread::sleep(interval);
+            let mut child = self.spawn()?;
+            self.stdout = Some(BufReader::new(child.stdout.take().expect("Could not get command stdout")));
+            self.child = Some(child);
+        }
+        Ok(())
+    }
+}
+
+impl LineReader for CommandReader {
+    fn read_line(&mut self, buf: &mut Vec<u8>) -> std::io::Result<usize> {
+        match self.interval {
+            Some(interval) => {
+                loop {
+                    if let Some(line) = self.pending.pop_front() {
+                        buf.extend_from_slice(&line);
+                        return Ok(line.len());
+                    }
+                    self.read_run(interval)?;
+                    // A run that printed nothing still replaces the old output
+                    if self.truncated && self.pending.is_empty() {
+                        return Ok(0);
+                    }
+                }
+            }
+            None => {
+                match self.stdout.as_mut() {
+                    Some(stdout) => stdout.read_until(b'\n', buf),
+                    None => Ok(0),
+                }
+            }
+        }
+    }
+
+    fn take_truncated(&mut self) -> bool {
+        std::mem::take(&mut self.truncated)
+    }
+
+    fn reload_message(&self) -> String {
+        format!("refreshed ({})", chrono::Local::now().format("%H:%M:%S"))
+    }
+
+    fn reload_len(&self) -> Option<usize> {
+        self.reload_len
+    }
+
+    fn end_message(&mut self) -> Option<String> {
+        match self.child.as_mut()?.wait() {
+            Ok(status) => Some(format!("command exited ({})", status)),
+            Err(e) => Some(format!("could not get command exit status: {}", e)),
+        }
+    }
+}
+
+pub const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+// Like less, a NUL byte or more than a few control chars that don't turn up in text (i.e. not whitespace, escapes
+// or backspaces) means binary. Bytes >= 0x80 don't count since they're most likely UTF-8
+fn is_binary(buf: &[u8]) -> bool {
+    if buf.contains(&0) {
+        return true;
+    }
+    let n_control = buf.iter().filter(|&&b| (b < 0x20 && !b"\t\n\r\x0b\x0c\x08\x1b".contains(&b)) || b == 0x7f).count();
+    n_control > 5 && n_control * 20 > buf.len()
+}
+
 pub struct FileReader {
-    reader: BufReader<File>,
+    reader: Box<dyn BufRead + Send>,
 }
 
 impl FileReader {
-    pub fn new(file: File) -> Self {
-        let reader = BufReader::new(file);
+    // Gzipped files (e.g. rotated logs) are decompressed transparently
+    // Files in another encoding (going by the encoding passed in, or else a byte order mark) are decoded to UTF-8
+    // before they're split into lines, anything else is passed through as it is
+    pub fn new(file: File, encoding: Option<&'static encoding_rs::Encoding>) -> Self {
+        let mut reader = BufReader::new(file);
+
+        // Peeking through the buffer rather than seeking means this also works on pipes
+        let is_gzip = reader.fill_buf().map(|buf| buf.starts_with(&GZIP_MAGIC)).unwrap_or(false);
+        let reader: Box<dyn BufRead + Send> = if is_gzip {
+            Box::new(BufReader::new(MultiGzDecoder::new(reader)))
+        } else {
+            Box::new(reader)
+        };
+        // UTF-8 is left alone even with a BOM so invalid bytes are handled the same as they would be without one
+        let decoder = DecodeReaderBytesBuilder::new()
+            .encoding(encoding)
+            .utf8_passthru(true)
+            .strip_bom(true)
+            .build(reader);
+        let reader = Box::new(BufReader::new(decoder));
 
         FileReader {
             reader,
@@ -43,64 +225,327 @@ impl FileReader {
 }
 
 impl LineReader for FileReader {
-    fn read_line(&mut self, buf: &mut String) -> std::io::Result<usize> {
-        self.reader.read_line(buf)
+    fn read_line(&mut self, buf: &mut Vec<u8>) -> std::io::Result<usize> {
+        self.reader.read_until(b'\n', buf)
+    }
+
+    // After decompression, a gzipped text file is still text
+    fn looks_binary(&mut self) -> bool {
+        self.reader.fill_buf().map(is_binary).unwrap_or(false)
+    }
+}
+
+// Named pipes are opened on the first read rather than up front, since opening one waits for a writer
+// With --watch, the writer closing the pipe isn't the end: like tail -f, it's opened again to wait for the next one
+pub struct FifoReader {
+    path: String,
+    reopen: bool,
+    reader: Option<BufReader<File>>,
+}
+
+impl FifoReader {
+    pub fn new(path: &str, reopen: bool) -> Self {
+        FifoReader {
+            path: path.to_string(),
+            reopen,
+            reader: None,
+        }
+    }
+}
+
+impl LineReader for FifoReader {
+    fn read_line(&mut self, buf: &mut Vec<u8>) -> std::io::Result<usize> {
+        loop {
+            let reader = match &mut self.reader {
+                Some(reader) => reader,
+                None => self.reader.insert(BufReader::new(File::open(&self.path)?)),
+            };
+            let n = reader.read_until(b'\n', buf)?;
+            if n > 0 || !self.reopen {
+                return Ok(n);
+            }
+            self.reader = None;
+        }
     }
 }
 
+// What a file watcher sends when the file changes
+type WatchEvents = mpsc::Receiver<notify::Result<notify::Event>>;
+
 pub struct WatchingFileReader {
-    file: File,
+    path: String,
+    poll_interval: Duration,
+    // Kept across calls so a burst of appended lines doesn't mean a seek and a fresh buffer per line
+    reader: BufReader<File>,
     offset: usize,
-    rx: mpsc::Receiver<notify::Result<notify::Event>>,
-    watcher: notify::PollWatcher,
+    // Whatever we've read of a line that hasn't been finished yet
+    partial: Vec<u8>,
+    truncated: bool,
+    // Set once we've reported the file as deleted, the next read waits for it to be recreated
+    removed: bool,
+    // Why the file was last read from the start again
+    reload_message: &'static str,
+    rx: WatchEvents,
+    // Never read, but dropping the watcher would stop the watch
+    _watcher: Box<dyn Watcher + Send>,
 }
 
 impl WatchingFileReader {
-    pub fn new(file: File, path: &str) -> Self {
-        let (watcher_tx, watcher_rx) = mpsc::channel::<notify::Result<notify::Event>>();
-        let (tx, rx) = mpsc::channel::<notify::Result<notify::Event>>();
-
-        // I've been having difficulties with recommended_watcher so polling it is :/
-        let mut watcher = notify::PollWatcher::new(watcher_tx,Config::default().with_poll_interval(Duration::from_millis(500))).expect("Could not create file watcher");
-        watcher.watch(Path::new(path), RecursiveMode::NonRecursive).expect("Could not watch file");
-
-        thread::spawn(move || {
-            let msg = watcher_rx.recv();
-            if let Ok(v) = msg {
-                tx.send(v).expect("Could not send file change event");
-            } else {
-                eprintln!("File watcher error: {:?}", msg);
-            }
+    // Fails if the file can't be watched, e.g. it's been removed since it was opened
+    pub fn new(file: File, path: &str, poll_interval: Duration) -> std::io::Result<Self> {
+        let (rx, watcher) = watch(path, poll_interval)?;
+        Ok(WatchingFileReader {
+            path: path.to_string(),
+            poll_interval,
+            reader: BufReader::new(file),
+            offset: 0,
+            partial: Vec::new(),
+            truncated: false,
+            removed: false,
+            reload_message: "file truncated, reloading",
+            rx,
+            _watcher: watcher,
+        })
+    }
+
+    // Starts over on whatever's at the path now, e.g. after log rotation moved the old file out of the way
+    // The watch is set up again too, since it followed the old file. The file can be removed again before that's
+    // done, then this fails with NotFound and the next read waits for it to come back, like it does the first time
+    fn reopen(&mut self) -> std::io::Result<()> {
+        let result = File::open(&self.path).and_then(|file| {
+            let (rx, watcher) = watch(&self.path, self.poll_interval)?;
+            self.reader = BufReader::new(file);
+            self.offset = 0;
+            self.partial.clear();
+            self.truncated = true;
+            self.reload_message = "file recreated, reloading";
+            (self.rx, self._watcher) = (rx, watcher);
+            Ok(())
         });
+        if result.as_ref().is_err_and(|e| e.kind() == std::io::ErrorKind::NotFound) {
+            self.removed = true;
+        }
+        result
+    }
+}
 
+// Uses the platform's native file watching (inotify, FSEvents, etc) where possible, since polling adds up to
+// poll_interval of lag. That isn't available everywhere (e.g. some network filesystems or WSL mounts of
+// Windows drives) so if it can't be set up we fall back to polling the file every poll_interval
+fn watch(path: &str, poll_interval: Duration) -> std::io::Result<(WatchEvents, Box<dyn Watcher + Send>)> {
+    let (tx, rx) = mpsc::channel::<notify::Result<notify::Event>>();
 
-        WatchingFileReader {
-            file,
-            offset: 0,
-            rx,
-            watcher,
+    let watcher = match watch_natively(path, tx.clone()) {
+        Ok(watcher) => Box::new(watcher) as Box<dyn Watcher + Send>,
+        Err(_) => {
+            let mut watcher = notify::PollWatcher::new(tx, Config::default().with_poll_interval(poll_interval)).map_err(watch_error)?;
+            watcher.watch(Path::new(path), RecursiveMode::NonRecursive).map_err(watch_error)?;
+            Box::new(watcher)
         }
+    };
+    Ok((rx, watcher))
+}
+
+// A file that's gone is NotFound like it would be from opening it, so callers can wait for it to come back
+fn watch_error(e: notify::Error) -> std::io::Error {
+    match e.kind {
+        notify::ErrorKind::Io(e) => e,
+        notify::ErrorKind::PathNotFound => std::io::Error::new(std::io::ErrorKind::NotFound, "file removed, waiting for recreation"),
+        _ => std::io::Error::other(e.to_string()),
     }
 }
 
+fn watch_natively(path: &str, tx: mpsc::Sender<notify::Result<notify::Event>>) -> notify::Result<notify::RecommendedWatcher> {
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher.watch(Path::new(path), RecursiveMode::NonRecursive)?;
+    Ok(watcher)
+}
+
+// Whether the path still points at the file we have open, i.e. it hasn't been moved away and replaced
+#[cfg(unix)]
+fn is_same_file(path_metadata: &std::fs::Metadata, file_metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    path_metadata.dev() == file_metadata.dev() && path_metadata.ino() == file_metadata.ino()
+}
+
+// Windows won't let an open file be moved or deleted anyway
+#[cfg(not(unix))]
+fn is_same_file(_path_metadata: &std::fs::Metadata, _file_metadata: &std::fs::Metadata) -> bool {
+    true
+}
+
 impl LineReader for WatchingFileReader {
-    fn read_line(&mut self, buf: &mut String) -> std::io::Result<usize> {
-        while self.file.metadata()?.len() <= self.offset as u64 {
-            match &self.rx.recv() {
-                Ok(_) => {
+    // Returns as soon as a complete line is available, and only waits for a change event once we've caught up
+    // If the file is deleted this returns a NotFound error once, and the next call waits for it to be recreated
+    fn read_line(&mut self, buf: &mut Vec<u8>) -> std::io::Result<usize> {
+        if self.removed {
+            while !Path::new(&self.path).exists() {
+                std::thread::sleep(self.poll_interval);
+            }
+            // If it's gone again already this sets removed back
+            self.removed = false;
+            self.reopen()?;
+        }
+
+        loop {
+            let file_metadata = self.reader.get_ref().metadata()?;
+            let len = file_metadata.len();
+            // The file got smaller, so it was truncated or rotated (copytruncate style) and we start over
+            if len < self.offset as u64 {
+                self.reader.seek(std::io::SeekFrom::Start(0))?;
+                self.offset = 0;
+                self.partial.clear();
+                self.truncated = true;
+                self.reload_message = "file truncated, reloading";
+            }
+
+            if len > self.offset as u64 {
+                let n = self.reader.read_until(b'\n', &mut self.partial)?;
+                self.offset += n;
+                if self.partial.ends_with(b"\n") {
+                    let n = self.partial.len();
+                    buf.append(&mut self.partial);
+                    return Ok(n);
+                }
+                if n > 0 {
+                    // Only part of a line has been written so far, see if there's more before waiting
                     continue;
                 }
-                Err(e) => {
-                    eprintln!("File changed, error: {:?}", e);
-                    return Err(std::io::Error::new(std::io::ErrorKind::Other, e.to_string()));
+            }
+
+            // We've caught up with the file we have open, but the path may not point at it any more
+            match std::fs::metadata(&self.path) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    self.removed = true;
+                    return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "file removed, waiting for recreation"));
+                }
+                Ok(path_metadata) if !is_same_file(&path_metadata, &file_metadata) => {
+                    self.reopen()?;
+                    continue;
                 }
+                _ => {}
+            }
+
+            if let Err(e) = self.rx.recv() {
+                return Err(std::io::Error::other(e.to_string()));
             }
         }
+    }
+
+    fn take_truncated(&mut self) -> bool {
+        std::mem::take(&mut self.truncated)
+    }
+
+    fn reload_message(&self) -> String {
+        self.reload_message.to_string()
+    }
+
+    fn looks_binary(&mut self) -> bool {
+        self.reader.fill_buf().map(is_binary).unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("lesser-test-{}-{}", std::process::id(), name))
+    }
+
+    fn read_line(reader: &mut impl LineReader) -> Vec<u8> {
+        let mut buf = Vec::new();
+        reader.read_line(&mut buf).expect("Could not read test line");
+        buf
+    }
+
+    #[test]
+    fn watched_file_that_is_truncated_is_read_from_the_start() {
+        let path = test_path("truncate");
+        std::fs::write(&path, "a\nb\n").unwrap();
+        let mut reader = WatchingFileReader::new(File::open(&path).unwrap(), path.to_str().unwrap(), Duration::from_millis(10)).unwrap();
+        assert_eq!(read_line(&mut reader), b"a\n");
+        assert_eq!(read_line(&mut reader), b"b\n");
+        assert!(!reader.take_truncated());
+
+        std::fs::write(&path, "c\n").unwrap();
+        assert_eq!(read_line(&mut reader), b"c\n");
+        assert!(reader.take_truncated());
+        assert_eq!(reader.reload_message(), "file truncated, reloading");
+        // Only reported the once
+        assert!(!reader.take_truncated());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn utf16_with_a_bom_is_decoded() {
+        let path = test_path("utf16");
+        let mut contents = vec![0xff, 0xfe];
+        contents.extend("hé\nthere\n".encode_utf16().flat_map(u16::to_le_bytes));
+        std::fs::write(&path, contents).unwrap();
+        let mut reader = FileReader::new(File::open(&path).unwrap(), None);
+        assert!(!reader.looks_binary());
+        assert_eq!(read_line(&mut reader), "hé\n".as_bytes());
+        assert_eq!(read_line(&mut reader), b"there\n");
+        assert_eq!(read_line(&mut reader), b"");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn watched_file_that_is_deleted_is_read_again_once_recreated() {
+        let path = test_path("recreate");
+        std::fs::write(&path, "a\n").unwrap();
+        let mut reader = WatchingFileReader::new(File::open(&path).unwrap(), path.to_str().unwrap(), Duration::from_millis(10)).unwrap();
+        assert_eq!(read_line(&mut reader), b"a\n");
+
+        std::fs::remove_file(&path).unwrap();
+        let mut buf = Vec::new();
+        let e = reader.read_line(&mut buf).expect_err("Deleted file was still read");
+        assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+
+        std::fs::write(&path, "b\n").unwrap();
+        assert_eq!(read_line(&mut reader), b"b\n");
+        assert!(reader.take_truncated());
+        assert_eq!(reader.reload_message(), "file recreated, reloading");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    // Each writer opens the pipe, writes a line and closes it again
+    #[cfg(unix)]
+    #[test]
+    fn named_pipe_is_reopened_for_the_next_writer() {
+        let path = test_path("fifo");
+        let status = std::process::Command::new("mkfifo").arg(&path).status().expect("Could not run mkfifo");
+        assert!(status.success());
+        let writer_path = path.clone();
+        let writer = std::thread::spawn(move || {
+            for line in ["first\n", "second\n"] {
+                std::fs::write(&writer_path, line).unwrap();
+                // Give the reader a chance to see the pipe closed before the next writer opens it
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        });
+
+        let mut reader = FifoReader::new(path.to_str().unwrap(), true);
+        assert_eq!(read_line(&mut reader), b"first\n");
+        assert_eq!(read_line(&mut reader), b"second\n");
+        writer.join().unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    // Only the first run prints anything, the marker file is there from the second run on
+    #[cfg(unix)]
+    #[test]
+    fn interval_run_that_prints_nothing_is_still_a_reload() {
+        let marker = test_path("interval");
+        let command = format!("[ -e {0} ] || {{ touch {0}; echo a; }}", marker.display());
+        let mut reader = CommandReader::new(&command, Some(Duration::from_millis(10))).unwrap();
+        assert_eq!(read_line(&mut reader), b"a\n");
+        assert!(!reader.take_truncated());
 
-        let mut reader = BufReader::new(&self.file);
-        reader.seek(std::io::SeekFrom::Start(self.offset as u64))?;
-        let n = reader.read_line(buf)?;
-        self.offset += n;
-        Ok(n)
+        assert_eq!(read_line(&mut reader), b"");
+        assert!(reader.take_truncated());
+        assert_eq!(reader.reload_len(), Some(0));
+        std::fs::remove_file(marker).unwrap();
     }
 }
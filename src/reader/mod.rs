@@ -1,39 +1,348 @@
+pub mod line_index;
 pub mod line_reader;
 
+use std::borrow::Cow;
 use std::sync::{mpsc, Arc, Mutex};
-use crate::messaging::{TerminalThreadMessage};
-use crate::reader::line_reader::LineReader;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::TryRecvError;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::Duration;
+use crate::messaging::{ReaderThreadMessage, TerminalThreadMessage};
 use crate::Source;
+use line_index::LineIndex;
 
-pub fn reader_thread_fn(source: Arc<Source>, term_tx: mpsc::Sender<TerminalThreadMessage>) {
-    let mut line = String::new();
-    let mut reader = source.reader.lock().expect("Could not take lock in reader_thread");
-
-    while let Ok(n) = reader.read_line(&mut line) {
-        // This isn't really for Windows, it's for Windows terminal emulators running under WSL
-        // Since we're in raw mode the emulator won't know what to do with LF-style line endings
-        // It's entirely possible that this will break things for normal Unix terminals, so this
-        // might need revisiting
-        if !line.ends_with("\r\n") {
-            if line.ends_with('\n') {
-                line.pop();
-                line.push_str("\r\n");
-            } else if line.ends_with('\r') {
-                line.push_str("\n");
-            } else {
-                line.push_str("\r\n");
+// A source's lines, kept in memory or, for big files, read back from the file as they're needed
+#[derive(Clone)]
+pub enum Lines {
+    // Arc<str> rather than String so that a line can be shared rather than copied when it's shown in more than one
+    // place, at the cost of an extra pointer per line
+    Loaded(Vec<Arc<str>>),
+    Indexed(LineIndex),
+}
+
+impl Lines {
+    pub fn len(&self) -> usize {
+        match self {
+            Lines::Loaded(lines) => lines.len(),
+            Lines::Indexed(index) => index.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn line(&self, line_no: usize) -> Cow<'_, str> {
+        match self {
+            Lines::Loaded(lines) => Cow::Borrowed(&lines[line_no]),
+            Lines::Indexed(index) => Cow::Owned(index.line(line_no)),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Cow<'_, str>> {
+        (0..self.len()).map(|line_no| self.line(line_no))
+    }
+}
+
+// The view of all files together, which every reader adds its lines to
+#[derive(Clone)]
+pub struct Aggregate {
+    pub source: Arc<Source>,
+    pub index: usize,
+    // Start each line with the name of the source it came from, like grep does with several files
+    pub prefix_source: bool,
+    // Collapse a line that's the same as the one before it into that one, with a count of how many times it came in
+    pub uniq: bool,
+    // For uniq, only touched with the aggregate's lines locked
+    pub last_line: Arc<Mutex<Option<Repeat>>>,
+}
+
+// The last line added to the aggregate (before its count) and how many times in a row it's come in
+pub struct Repeat {
+    line: Arc<str>,
+    count: usize,
+}
+
+// How long lines copied to --output can sit in the buffer before they're written out
+const TEE_FLUSH_INTERVAL: Duration = Duration::from_millis(500);
+
+// With --output, everything read is copied to a file as well, like tee. Every reader shares the one file, so lines
+// from different sources go in the order they were read, same as in the view of all files together
+pub struct Tee {
+    path: String,
+    writer: BufWriter<File>,
+    // Once writing fails it's given up on, rather than warning about every line
+    failed: bool,
+}
+
+impl Tee {
+    pub fn new(path: &str, file: File) -> Self {
+        Tee {
+            path: path.to_string(),
+            writer: BufWriter::new(file),
+            failed: false,
+        }
+    }
+}
+
+// Flushes --output every TEE_FLUSH_INTERVAL, so what's been read gets to the file even while the readers are waiting
+// on more input. Like the readers this isn't joined, it stops once the terminal thread has gone or writing fails
+pub fn tee_flush_thread_fn(tee: Arc<Mutex<Tee>>, term_tx: mpsc::Sender<TerminalThreadMessage>) {
+    loop {
+        std::thread::sleep(TEE_FLUSH_INTERVAL);
+        if let Err(message) = flush_tee(&tee) {
+            let _ = term_tx.send(TerminalThreadMessage::Status(message));
+            break;
+        }
+        if tee.lock().expect("Could not take lock in tee_flush_thread").failed {
+            break;
+        }
+    }
+}
+
+// This can't be interrupted while it's blocked in read_line, so it isn't joined on exit. But it checks for
+// ReaderThreadMessage::Exit between lines so that a big file doesn't keep getting read after we quit
+pub fn reader_thread_fn(source: Arc<Source>, source_index: usize, aggregate: Option<Aggregate>, max_lines: Option<usize>, tee: Option<Arc<Mutex<Tee>>>, term_tx: mpsc::Sender<TerminalThreadMessage>, reader_rx: mpsc::Receiver<ReaderThreadMessage>) {
+    let mut buf = Vec::<u8>::new();
+    let Some(reader) = &source.reader else {
+        return;
+    };
+    let mut reader = reader.lock().expect("Could not take lock in reader_thread");
+    // An indexed source's lines are only read back from the file as they're needed, copying them all into the
+    // aggregate would be keeping them in memory after all
+    let aggregate = aggregate.filter(|_| matches!(*source.lines.lock().expect("Could not take lock in reader_thread"), Lines::Loaded(_)));
+
+    loop {
+        let n = match reader.read_line(&mut buf) {
+            Ok(n) => n,
+            // A watched file was deleted, reading again waits for it to come back
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let _ = term_tx.send(TerminalThreadMessage::Status(format!("{}: {}", source.name, e)));
+                buf.clear();
+                continue;
             }
+            Err(_) => break,
+        };
+        let truncated = reader.take_truncated();
+        // A reload can come with no line, when a command run with --interval prints nothing this time
+        if n == 0 && !truncated {
+            break;
+        }
+        if n > 0 {
+            if let Some(tee) = &tee {
+                if let Err(message) = tee_line(tee, &buf) {
+                    let _ = term_tx.send(TerminalThreadMessage::Status(message));
+                }
+            }
+        }
+        let mut old_len = 0;
+        source.bytes_read.fetch_add(n, Ordering::Relaxed);
+        if let Some(aggregate) = &aggregate {
+            aggregate.source.bytes_read.fetch_add(n, Ordering::Relaxed);
+        }
+        {
+            let mut lines = source.lines.lock().expect("Could not take lock in reader_thread");
+            match &mut *lines {
+                Lines::Loaded(lines) => {
+                    if truncated {
+                        old_len = lines.len();
+                        lines.clear();
+                        source.dropped.store(0, Ordering::Relaxed);
+                    }
+                    if n > 0 {
+                        let line: Arc<str> = Arc::from(decode_line(&buf));
+                        if let Some(aggregate) = &aggregate {
+                            // A truncated file's old lines stay in the aggregate, which is a record of everything read so far
+                            let mut aggregate_lines = aggregate.source.lines.lock().expect("Could not take lock in reader_thread");
+                            // The aggregate is always kept in memory
+                            if let Lines::Loaded(aggregate_lines) = &mut *aggregate_lines {
+                                let aggregate_line = if aggregate.prefix_source {
+                                    Arc::from(format!("{}:{}", source.name, line))
+                                } else {
+                                    line.clone()
+                                };
+                                add_to_aggregate(aggregate, aggregate_lines, aggregate_line);
+                                if let Some(max_lines) = max_lines {
+                                    drop_oldest_lines(aggregate_lines, &aggregate.source.dropped, max_lines);
+                                }
+                            }
+                        }
+                        lines.push(line);
+                        if let Some(max_lines) = max_lines {
+                            drop_oldest_lines(lines, &source.dropped, max_lines);
+                        }
+                    }
+                }
+                // Only plain files that aren't watched are indexed, so there's no truncating to deal with, and only
+                // without --max-lines, so no lines to drop either
+                Lines::Indexed(index) => index.add_line(n),
+            }
+        }
+
+        if truncated {
+            let _ = term_tx.send(TerminalThreadMessage::Reloaded {
+                source_index,
+                old_len,
+                new_len: reader.reload_len(),
+                message: format!("{}: {}", source.name, reader.reload_message()),
+            });
         }
 
+        buf.clear();
+        if term_tx.send(TerminalThreadMessage::Read(source_index)).is_err() {
+            break;
+        }
+        if let Some(aggregate) = &aggregate {
+            let _ = term_tx.send(TerminalThreadMessage::Read(aggregate.index));
+        }
+
+        match reader_rx.try_recv() {
+            Ok(ReaderThreadMessage::Exit) | Err(TryRecvError::Disconnected) => {
+                break;
+            }
+            Err(TryRecvError::Empty) => {}
+        }
+    }
+
+    let tee_result = tee.as_ref().map_or(Ok(()), |tee| flush_tee(tee));
+    let _ = term_tx.send(TerminalThreadMessage::Eof(source_index));
+    if let Err(message) = tee_result {
+        let _ = term_tx.send(TerminalThreadMessage::Status(message));
+    }
+    // After Eof, otherwise the redraw for it would cover this up
+    if let Some(message) = reader.end_message() {
+        let _ = term_tx.send(TerminalThreadMessage::Status(format!("{}: {}", source.name, message)));
+    }
+}
+
+// The line as the reader hands it over, line ending and all. That's after any decompressing and decoding, so a
+// gzipped file or one in another encoding is saved as plain UTF-8, but invalid UTF-8 is kept rather than replaced
+// Only the first error is returned, after that the file is left alone
+pub fn tee_line(tee: &Mutex<Tee>, buf: &[u8]) -> Result<(), String> {
+    let mut tee = tee.lock().expect("Could not take lock in tee_line");
+    if tee.failed {
+        return Ok(());
+    }
+    tee.writer.write_all(buf).map_err(|e| tee_error(&mut tee, e))
+}
+
+pub fn flush_tee(tee: &Mutex<Tee>) -> Result<(), String> {
+    let mut tee = tee.lock().expect("Could not take lock in flush_tee");
+    if tee.failed {
+        return Ok(());
+    }
+    tee.writer.flush().map_err(|e| tee_error(&mut tee, e))
+}
+
+fn tee_error(tee: &mut Tee, e: std::io::Error) -> String {
+    tee.failed = true;
+    format!("Could not write to {}, no longer saving input there: {}", tee.path, e)
+}
+
+fn add_to_aggregate(aggregate: &Aggregate, lines: &mut Vec<Arc<str>>, line: Arc<str>) {
+    if !aggregate.uniq {
+        lines.push(line);
+        return;
+    }
+
+    let mut last_line = aggregate.last_line.lock().expect("Could not take lock in add_to_aggregate");
+    match (last_line.as_mut(), lines.last_mut()) {
+        // The line on screen is swapped for one with the new count, like uniq -c
+        (Some(last), Some(shown)) if last.line == line => {
+            last.count += 1;
+            *shown = Arc::from(format!("{} (x{})", last.line, last.count));
+        }
+        _ => {
+            *last_line = Some(Repeat { line: line.clone(), count: 1 });
+            lines.push(line);
+        }
+    }
+}
+
+// With --max-lines, the oldest lines are dropped once there are more than max_lines. They go a batch at a time,
+// since taking lines off the front of a Vec moves all the rest
+fn drop_oldest_lines(lines: &mut Vec<Arc<str>>, dropped: &AtomicUsize, max_lines: usize) {
+    if lines.len() <= max_lines {
+        return;
+    }
+    let n = lines.len() - max_lines + max_lines / 16;
+    lines.drain(..n);
+    dropped.fetch_add(n, Ordering::Relaxed);
+}
+
+// Reads everything there is synchronously, for when we don't need to page, e.g. --dump
+// This doesn't return for sources that never end, like watched files
+pub fn read_to_end(source: &Source) {
+    let mut buf = Vec::<u8>::new();
+    let Some(reader) = &source.reader else {
+        return;
+    };
+    let mut reader = reader.lock().expect("Could not take lock in read_to_end");
+    let mut lines = source.lines.lock().expect("Could not take lock in read_to_end");
+    while let Ok(n) = reader.read_line(&mut buf) {
         if n == 0 {
             break;
         }
-        {
-            let mut lines: std::sync::MutexGuard<'_, Vec<String>> = source.lines.lock().expect("Could not take lock in reader_thread");
-            lines.push(line.clone());
+        match &mut *lines {
+            Lines::Loaded(lines) => lines.push(Arc::from(decode_line(&buf))),
+            Lines::Indexed(index) => index.add_line(n),
         }
+        buf.clear();
+    }
+}
+
+// Lines are stored without their line ending so searching and measuring them only sees the content, the terminal
+// code writes the ending the terminal needs (\r\n in raw mode, since the terminal won't add the \r itself then)
+fn decode_line(buf: &[u8]) -> String {
+    let buf = buf.strip_suffix(b"\n").unwrap_or(buf);
+    let buf = buf.strip_suffix(b"\r").unwrap_or(buf);
+    // Invalid UTF-8 (e.g. a stray Latin-1 byte) is shown as the replacement char rather than ending the read
+    String::from_utf8_lossy(buf).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_utf8_is_replaced_rather_than_ending_the_read() {
+        assert_eq!(decode_line(b"caf\xe9 ok\n"), "caf\u{fffd} ok");
+        assert_eq!(decode_line(b"a\xff\xfeb\n"), "a\u{fffd}\u{fffd}b");
+    }
+
+    #[test]
+    fn line_endings_are_not_rewritten() {
+        // The terminal code writes \r\n itself in raw mode, so a line read on its own gets no \r added
+        assert!(!decode_line(b"unix\n").contains('\r'));
+        assert!(!decode_line(b"no ending").contains('\r'));
+    }
+
+    #[test]
+    fn line_endings_are_stripped() {
+        assert_eq!(decode_line(b"unix\n"), "unix");
+        assert_eq!(decode_line(b"windows\r\n"), "windows");
+        assert_eq!(decode_line(b"old mac\r"), "old mac");
+        assert_eq!(decode_line(b"last line"), "last line");
+        // Only the ending, a \r in the middle of the line is left for caret notation
+        assert_eq!(decode_line(b"a\rb\n"), "a\rb");
+        // So a search anchored to the end of the line matches whatever the ending was
+        let re = regex::Regex::new("line$").unwrap();
+        assert!(re.is_match(&decode_line(b"last line\r\n")));
+    }
+
+    #[test]
+    fn max_lines_drops_the_oldest_lines_a_batch_at_a_time() {
+        let mut lines = (0..32).map(|i| Arc::from(i.to_string())).collect::<Vec<Arc<str>>>();
+        let dropped = AtomicUsize::new(0);
+        drop_oldest_lines(&mut lines, &dropped, 32);
+        assert_eq!(lines.len(), 32);
 
-        line.clear();
-        term_tx.send(TerminalThreadMessage::Read).expect("Could not send message to terminal thread");
+        lines.push(Arc::from("32"));
+        drop_oldest_lines(&mut lines, &dropped, 32);
+        // One over, plus a sixteenth of the limit so the next few lines don't each mean another drain
+        assert_eq!(lines.len(), 30);
+        assert_eq!(&*lines[0], "3");
+        assert_eq!(dropped.load(Ordering::Relaxed), 3);
     }
-}
\ No newline at end of file
+}
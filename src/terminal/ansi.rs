@@ -0,0 +1,357 @@
+use std::borrow::Cow;
+use unicode_width::UnicodeWidthChar;
+
+const ESC: char = '\x1b';
+
+// Returns the length in bytes of the escape sequence at the start of `s`, if there is one
+// Only CSI sequences (ESC [ ... final byte) are parsed properly, any other escape is treated as ESC plus one char
+fn escape_len(s: &str) -> Option<usize> {
+    let mut chars = s.char_indices();
+    match chars.next() {
+        Some((_, ESC)) => {}
+        _ => return None,
+    }
+
+    match chars.next() {
+        Some((_, '[')) => {
+            for (i, c) in chars {
+                if ('\x40'..='\x7e').contains(&c) {
+                    return Some(i + c.len_utf8());
+                }
+            }
+            Some(s.len())
+        }
+        Some((i, c)) => Some(i + c.len_utf8()),
+        None => Some(s.len()),
+    }
+}
+
+fn is_sgr(sequence: &str) -> bool {
+    sequence.starts_with("\x1b[") && sequence.ends_with('m')
+}
+
+// Drops escape sequences that would move the cursor or otherwise scramble the screen
+// SGR (colour/style) sequences are kept if `keep_sgr` is set, otherwise they're stripped too
+pub fn filter_escapes(line: &str, keep_sgr: bool) -> Cow<'_, str> {
+    if !line.contains(ESC) {
+        return Cow::Borrowed(line);
+    }
+
+    let mut filtered = String::with_capacity(line.len());
+    let mut i = 0;
+    while i < line.len() {
+        if let Some(len) = escape_len(&line[i..]) {
+            let sequence = &line[i..i + len];
+            if keep_sgr && is_sgr(sequence) {
+                filtered.push_str(sequence);
+            }
+            i += len;
+        } else {
+            let c = line[i..].chars().next().unwrap();
+            filtered.push(c);
+            i += c.len_utf8();
+        }
+    }
+
+    Cow::Owned(filtered)
+}
+
+// Number of columns the line takes up on screen, escape sequences don't count
+pub fn visible_width(line: &str) -> usize {
+    let mut width = 0;
+    let mut i = 0;
+    while i < line.len() {
+        if let Some(len) = escape_len(&line[i..]) {
+            i += len;
+        } else {
+            let c = line[i..].chars().next().unwrap();
+            width += c.width().unwrap_or(0);
+            i += c.len_utf8();
+        }
+    }
+    width
+}
+
+// Splits the line after `width` visible columns, never in the middle of an escape sequence or a multibyte char
+// Wide chars (e.g. CJK) that don't fit in what's left of the row get pushed onto the next one
+pub fn split_at_width(line: &str, width: usize) -> (&str, &str) {
+    let mut seen = 0;
+    let mut i = 0;
+    while i < line.len() {
+        if let Some(len) = escape_len(&line[i..]) {
+            i += len;
+        } else {
+            let c = line[i..].chars().next().unwrap();
+            let char_width = c.width().unwrap_or(0);
+            // Always take at least one char, otherwise a row narrower than a wide char would never make progress
+            if seen + char_width > width && seen > 0 {
+                break;
+            }
+            seen += char_width;
+            i += c.len_utf8();
+        }
+    }
+    line.split_at(i)
+}
+
+// Like split_at_width, but breaks after the last whitespace that fits so words aren't cut in half
+// Falls back to a hard break for words longer than the whole row
+pub fn split_at_word(line: &str, width: usize) -> (&str, &str) {
+    let (row, rest) = split_at_width(line, width);
+    if rest.is_empty() {
+        return (row, rest);
+    }
+
+    let mut last_break = None;
+    let mut i = 0;
+    while i < row.len() {
+        if let Some(len) = escape_len(&row[i..]) {
+            i += len;
+        } else {
+            let c = row[i..].chars().next().unwrap();
+            i += c.len_utf8();
+            if c.is_whitespace() {
+                last_break = Some(i);
+            }
+        }
+    }
+
+    match last_break {
+        Some(i) => line.split_at(i),
+        None => (row, rest),
+    }
+}
+
+// The SGR sequences still in effect at the end of `line`, i.e. everything since the last reset
+// Used to re-apply the colour at the start of a wrapped row
+pub fn active_sgr(line: &str) -> String {
+    let mut active = String::new();
+    let mut i = 0;
+    while i < line.len() {
+        if let Some(len) = escape_len(&line[i..]) {
+            let sequence = &line[i..i + len];
+            if sequence == "\x1b[m" || sequence == "\x1b[0m" {
+                active.clear();
+            } else if is_sgr(sequence) {
+                active.push_str(sequence);
+            }
+            i += len;
+        } else {
+            i += line[i..].chars().next().unwrap().len_utf8();
+        }
+    }
+    active
+}
+
+// Shows control chars as ^A, ^G and so on like less does, printed raw they'd ring the bell or move the cursor
+// Tabs are left for expand_tabs and escape sequences are left for filter_escapes to deal with
+pub fn caret_notation(line: &str) -> Cow<'_, str> {
+    if !line.chars().any(is_caret_char) {
+        return Cow::Borrowed(line);
+    }
+
+    let mut notated = String::with_capacity(line.len() + 8);
+    let mut i = 0;
+    while i < line.len() {
+        if let Some(len) = escape_len(&line[i..]) {
+            notated.push_str(&line[i..i + len]);
+            i += len;
+        } else {
+            let c = line[i..].chars().next().unwrap();
+            if is_caret_char(c) {
+                notated.push('^');
+                // ^? for DEL, ^@ through ^_ for the rest
+                notated.push(((c as u8) ^ 0x40) as char);
+            } else {
+                notated.push(c);
+            }
+            i += c.len_utf8();
+        }
+    }
+
+    Cow::Owned(notated)
+}
+
+fn is_caret_char(c: char) -> bool {
+    c.is_ascii_control() && c != '\t' && c != ESC
+}
+
+// Like less, X\bX is shown as a bold X and _\bX as an underlined one, which is how man (via nroff) and other old
+// tools that expect a printer style these. Backspaces that aren't part of one of these are left for caret_notation
+pub fn overstrike(line: &str) -> Cow<'_, str> {
+    if !line.contains('\x08') {
+        return Cow::Borrowed(line);
+    }
+
+    let chars = line.chars().collect::<Vec<char>>();
+    let mut struck = String::with_capacity(line.len());
+    let mut i = 0;
+    while i < chars.len() {
+        // Each char can be struck over several times, e.g. _\bX\bX for bold and underlined
+        let mut c = chars[i];
+        let mut bold = false;
+        let mut underline = false;
+        let mut end = i;
+        while end + 2 < chars.len() && chars[end + 1] == '\x08' {
+            let next = chars[end + 2];
+            if next == c {
+                bold = true;
+            } else if c == '_' {
+                underline = true;
+                c = next;
+            } else if next == '_' {
+                underline = true;
+            } else {
+                break;
+            }
+            end += 2;
+        }
+
+        if end == i {
+            struck.push(c);
+        } else {
+            struck.push_str(match (bold, underline) {
+                (true, true) => "\x1b[1;4m",
+                (true, false) => "\x1b[1m",
+                _ => "\x1b[4m",
+            });
+            struck.push(c);
+            struck.push_str("\x1b[22;24m");
+        }
+        i = end + 1;
+    }
+
+    Cow::Owned(struck)
+}
+
+// For hunting down stray whitespace, spaces are shown as · and tabs as → (padded out to the next tab stop as usual),
+// dimmed so they don't drown out the text. Whitespace at the end of the line is shown in reverse video instead
+// since it's usually a mistake. Like expand_tabs, this leaves no tabs behind
+pub fn show_whitespace(line: &str, tab_width: usize) -> String {
+    let trailing_start = line.trim_end_matches([' ', '\t']).len();
+    let mut shown = String::with_capacity(line.len() * 2);
+    let mut column = 0;
+    // How to end the run of whitespace we're in, if we're in one
+    let mut run_end: Option<&str> = None;
+    let mut i = 0;
+    while i < line.len() {
+        if let Some(len) = escape_len(&line[i..]) {
+            shown.push_str(&line[i..i + len]);
+            i += len;
+            continue;
+        }
+
+        let c = line[i..].chars().next().unwrap();
+        let is_whitespace = c == ' ' || c == '\t';
+        if is_whitespace && run_end.is_none() {
+            let (start, end) = if i >= trailing_start { ("\x1b[7m", "\x1b[27m") } else { ("\x1b[2m", "\x1b[22m") };
+            shown.push_str(start);
+            run_end = Some(end);
+        } else if !is_whitespace {
+            if let Some(end) = run_end.take() {
+                shown.push_str(end);
+            }
+        }
+
+        match c {
+            ' ' => {
+                shown.push('·');
+                column += 1;
+            }
+            '\t' => {
+                let n_spaces = tab_width - column % tab_width;
+                shown.push('→');
+                shown.extend(std::iter::repeat_n(' ', n_spaces - 1));
+                column += n_spaces;
+            }
+            _ => {
+                shown.push(c);
+                column += c.width().unwrap_or(0);
+            }
+        }
+        i += c.len_utf8();
+    }
+    if let Some(end) = run_end {
+        shown.push_str(end);
+    }
+
+    shown
+}
+
+// Replaces tabs with enough spaces to reach the next tab stop, so the width math agrees with what the terminal draws
+pub fn expand_tabs(line: &str, tab_width: usize) -> Cow<'_, str> {
+    if !line.contains('\t') {
+        return Cow::Borrowed(line);
+    }
+
+    let mut expanded = String::with_capacity(line.len());
+    let mut column = 0;
+    let mut i = 0;
+    while i < line.len() {
+        if let Some(len) = escape_len(&line[i..]) {
+            expanded.push_str(&line[i..i + len]);
+            i += len;
+        } else {
+            let c = line[i..].chars().next().unwrap();
+            if c == '\t' {
+                let n_spaces = tab_width - column % tab_width;
+                expanded.extend(std::iter::repeat_n(' ', n_spaces));
+                column += n_spaces;
+            } else {
+                expanded.push(c);
+                column += c.width().unwrap_or(0);
+            }
+            i += c.len_utf8();
+        }
+    }
+
+    Cow::Owned(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wide_chars_count_for_two_columns() {
+        assert_eq!(visible_width("日本語"), 6);
+        assert_eq!(visible_width("\x1b[31m日本\x1b[0m語"), 6);
+    }
+
+    #[test]
+    fn wide_chars_that_do_not_fit_go_on_the_next_row() {
+        assert_eq!(split_at_width("日本語のテキスト", 5), ("日本", "語のテキスト"));
+        assert_eq!(split_at_width("a日本", 2), ("a", "日本"));
+        // A row too narrow for even one wide char still takes it rather than never making progress
+        assert_eq!(split_at_width("日本", 1), ("日", "本"));
+    }
+
+    #[test]
+    fn tabs_go_to_the_next_tab_stop() {
+        assert_eq!(expand_tabs("a\tbc\td", 4), "a   bc  d");
+        assert_eq!(expand_tabs("\t\tx", 4), "        x");
+        // A tab right on a stop still moves a whole stop along
+        assert_eq!(expand_tabs("abcd\te", 4), "abcd    e");
+        // Escapes take up no columns and wide chars take up two
+        assert_eq!(expand_tabs("\x1b[31ma\x1b[0m\tb", 4), "\x1b[31ma\x1b[0m   b");
+        assert_eq!(expand_tabs("日\tb", 4), "日  b");
+    }
+
+    #[test]
+    fn word_wrap_breaks_after_the_last_space_that_fits() {
+        assert_eq!(split_at_word("one two three", 9), ("one two ", "three"));
+        assert_eq!(split_at_word("one two", 9), ("one two", ""));
+        // A word longer than the row is broken wherever the row ends
+        assert_eq!(split_at_word("abcdefghijkl mn", 5), ("abcde", "fghijkl mn"));
+    }
+
+    #[test]
+    fn overstrike_is_shown_as_bold_and_underline() {
+        assert_eq!(overstrike("_\x08t_\x08e_\x08x_\x08t"), "\x1b[4mt\x1b[22;24m\x1b[4me\x1b[22;24m\x1b[4mx\x1b[22;24m\x1b[4mt\x1b[22;24m");
+        assert_eq!(overstrike("B\x08Bo\x08old"), "\x1b[1mB\x1b[22;24m\x1b[1mo\x1b[22;24mld");
+        assert_eq!(overstrike("_\x08X\x08X"), "\x1b[1;4mX\x1b[22;24m");
+        // A backspace that isn't overstriking anything is left alone
+        assert_eq!(overstrike("a\x08b"), "a\x08b");
+        assert_eq!(overstrike("plain"), "plain");
+    }
+}
@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use crate::config;
+
+// Keys can be remapped in the config file, e.g.
+//
+//   [keys]
+//   quit = "x"
+//   down = ["e", "Ctrl-N"]
+//
+// A remapped key is translated into the built-in key for its action before it's handled, so any key that isn't
+// remapped keeps doing what it always did. Keys are looked up by code and whether Ctrl is held, Shift is already
+// part of the char
+pub type Keymap = HashMap<(KeyCode, bool), KeyEvent>;
+
+// Each action and the built-in key that does it, keep this in step with the key handlers in term_thread_fn
+const ACTIONS: &[(&str, &str)] = &[
+    ("quit", "q"),
+    ("down", "j"),
+    ("up", "k"),
+    ("half-page-down", "d"),
+    ("half-page-up", "u"),
+    ("page-down", "Space"),
+    ("page-up", "b"),
+    ("end", "End"),
+    ("start", "Home"),
+    ("go-to-line", "g"),
+    ("percent", "%"),
+    ("search", "/"),
+    ("search-backward", "?"),
+    ("regex-search", "r"),
+    ("next-match", "n"),
+    ("previous-match", "N"),
+    ("clear-highlights", "c"),
+    ("filter", "&"),
+    ("pipe", "|"),
+    ("previous-blank-line", "{"),
+    ("next-blank-line", "}"),
+    ("set-mark", "m"),
+    ("go-to-mark", "'"),
+    ("follow", "f"),
+    ("mouse", "z"),
+    ("line-numbers", "l"),
+    ("chop-long-lines", "x"),
+    ("word-wrap", "w"),
+    ("show-whitespace", "i"),
+    ("header", "t"),
+    ("next-source", "s"),
+    ("next-file", "]"),
+    ("previous-file", "["),
+    ("pick-source", "o"),
+    ("editor", "v"),
+    ("file-info", "="),
+    ("repaint", "Ctrl-L"),
+    ("help", "h"),
+];
+
+// Anything in the config that doesn't make sense is warned about and skipped
+pub fn keymap_from_config(config: &toml::Table) -> Keymap {
+    let mut keymap = Keymap::new();
+    let Some(keys) = config.get("keys") else {
+        return keymap;
+    };
+    let Some(keys) = keys.as_table() else {
+        config::warn("ignoring keys, it should be a table");
+        return keymap;
+    };
+
+    for (action, names) in keys {
+        let Some((_, builtin)) = ACTIONS.iter().find(|(name, _)| name == action) else {
+            config::warn(&format!("ignoring unknown action {}", action));
+            continue;
+        };
+        let (code, ctrl) = parse_key(builtin).expect("Could not parse built-in key");
+        let modifiers = if ctrl { KeyModifiers::CONTROL } else { KeyModifiers::NONE };
+
+        // Either one key or a list of them
+        let names = match names {
+            toml::Value::String(name) => vec![name.as_str()],
+            toml::Value::Array(names) => names.iter().filter_map(|name| name.as_str()).collect(),
+            _ => Vec::new(),
+        };
+        if names.is_empty() {
+            config::warn(&format!("ignoring {}, it should be a key name or a list of them", action));
+        }
+        for name in names {
+            match parse_key(name) {
+                Some(key) => {
+                    keymap.insert(key, KeyEvent::new(code, modifiers));
+                }
+                None => config::warn(&format!("ignoring unknown key {} for {}", name, action)),
+            }
+        }
+    }
+
+    keymap
+}
+
+// A single char, a named key like PageDown, or either of those after Ctrl-
+fn parse_key(name: &str) -> Option<(KeyCode, bool)> {
+    let (name, ctrl) = match name.strip_prefix("Ctrl-").or_else(|| name.strip_prefix("C-")) {
+        Some(name) => (name, true),
+        None => (name, false),
+    };
+
+    let mut chars = name.chars();
+    let code = match (chars.next(), chars.next()) {
+        // Ctrl-L and Ctrl-l are the same key, crossterm reports it as lowercase
+        (Some(c), None) if ctrl => KeyCode::Char(c.to_ascii_lowercase()),
+        (Some(c), None) => KeyCode::Char(c),
+        _ => match name.to_lowercase().as_str() {
+            "space" => KeyCode::Char(' '),
+            "up" => KeyCode::Up,
+            "down" => KeyCode::Down,
+            "left" => KeyCode::Left,
+            "right" => KeyCode::Right,
+            "pageup" | "pgup" => KeyCode::PageUp,
+            "pagedown" | "pgdn" => KeyCode::PageDown,
+            "home" => KeyCode::Home,
+            "end" => KeyCode::End,
+            "enter" => KeyCode::Enter,
+            "esc" | "escape" => KeyCode::Esc,
+            "tab" => KeyCode::Tab,
+            "backspace" => KeyCode::Backspace,
+            "delete" => KeyCode::Delete,
+            "insert" => KeyCode::Insert,
+            _ => return None,
+        },
+    };
+    Some((code, ctrl))
+}
+
+// The built-in key that a key has been remapped to, or the key itself if it hasn't been
+pub fn translate(keymap: &Keymap, event: KeyEvent) -> KeyEvent {
+    match keymap.get(&(event.code, event.modifiers.contains(KeyModifiers::CONTROL))) {
+        Some(builtin) => KeyEvent { code: builtin.code, modifiers: builtin.modifiers, ..event },
+        None => event,
+    }
+}
@@ -1,107 +1,891 @@
+mod ansi;
+pub mod keymap;
+pub mod theme;
+
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::io::{stdout, Write};
-use std::process::exit;
-use std::sync::{mpsc, Arc, Mutex};
-use std::thread;
-use std::time::Duration;
-use crossterm::event::{KeyEventKind, KeyModifiers};
-use crossterm::{execute, queue};
+use std::ops::{Deref, Range};
+use std::sync::{mpsc, Arc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use crossterm::event::{DisableMouseCapture, EnableMouseCapture, KeyEventKind, KeyModifiers};
+use crossterm::{execute, queue, Command};
 use crossterm::cursor::MoveTo;
-use crossterm::style::{Color, Print, ResetColor, SetBackgroundColor, SetForegroundColor};
+use crossterm::style::{Attribute, Print, ResetColor, SetAttribute, SetBackgroundColor, SetForegroundColor};
 use crossterm::terminal::{disable_raw_mode, enable_raw_mode, Clear, ClearType, DisableLineWrap, EnableLineWrap, EnterAlternateScreen, LeaveAlternateScreen};
+use rayon::prelude::*;
 use crate::{Source, TerminalThreadMessage};
+use crate::messaging::InputThreadMessage;
+use crate::reader::Lines;
+use theme::Theme;
 
-const PAGE_UP_SIZE: usize = 10;
 
-pub fn term_thread_fn(sources: &[Arc<Source>], term_rx: mpsc::Receiver<TerminalThreadMessage>) {
-    execute!(stdout(), EnterAlternateScreen).unwrap();
-    execute!(stdout(), DisableLineWrap).unwrap();
+// Capturing the mouse for the wheel stops the terminal from selecting text, so it can be turned off with `z`
+static MOUSE_CAPTURE: AtomicBool = AtomicBool::new(false);
+
+// How long [ and ] wait for another [ or ] with --section before they're taken as a key on their own
+const SECOND_KEY_TIMEOUT: Duration = Duration::from_millis(500);
+
+// Searches of more lines than this are split across threads, a chunk of lines to each
+const PARALLEL_SEARCH_LINES: usize = 50_000;
+const SEARCH_CHUNK_LINES: usize = 8192;
+
+// Shown with `h`, keep this in step with the key handlers in term_thread_fn
+const HELP: &[&str] = &[
+    "Keys (arrow keys, PgUp and PgDn scroll this help, any other key closes it)",
+    "",
+    "  q, Esc             Quit",
+    "  Up, Down, k, j     Line up/down",
+    "  u, d               Half page up/down, as do Ctrl-U, Ctrl-D, PgUp and PgDn",
+    "  N u, N d           Scroll N lines up/down, and keep scrolling by N from then on",
+    "  b, Space           Full page up/down",
+    "  Home               Go to start",
+    "  Enter, End, G      Go to end",
+    "  g                  Go to line, g again goes to start",
+    "  N followed by a key  Repeat Up/Down/k/j N times, or go to line N with g/G",
+    "  N%                 Go N percent of the way through",
+    "  /                  Search",
+    "  ?                  Search backward",
+    "  r                  Regex search",
+    "  Ctrl-S             Toggle case sensitivity while typing a search",
+    "  Ctrl-B             Toggle matching whole words only while typing a search",
+    "  Up, Down           Recall earlier searches while typing a search",
+    "  w                  While stepping through search results, save the matching lines to a file",
+    "  Left, Right, Home, End  Move the cursor while typing at a prompt",
+    "  Ctrl-W             Delete the word before the cursor at a prompt",
+    "  n, N               Repeat the last search forward/backward",
+    "  c                  Clear search highlights",
+    "  &                  Show only lines matching a pattern, empty to show everything",
+    "  |                  Pipe the search matches (or everything) to a shell command",
+    "  {, }               Previous/next blank line, as do Ctrl-Up and Ctrl-Down",
+    "  m, '               Set a mark/go to a mark, followed by a letter",
+    "  f                  Toggle follow mode",
+    "  z                  Toggle mouse wheel scrolling, turn it off to select text",
+    "  l                  Toggle line numbers",
+    "  x                  Toggle chopping long lines instead of wrapping them",
+    "  w                  Toggle wrapping long lines at word boundaries",
+    "  i                  Toggle showing spaces and tabs, and whitespace at the ends of lines",
+    "  N t                Pin the first N lines to the top, t on its own turns it off or on",
+    "  s                  Next source",
+    "  ], [               Next/previous file",
+    "  ]], [[             Next/previous line matching --section",
+    "  o                  Pick a source from a list",
+    "  v                  Open the file in $EDITOR",
+    "  =, Ctrl-G          Show the file name, position and size",
+    "  Ctrl-L             Repaint the screen",
+    "  h                  Show this help",
+];
+
+#[derive(Clone, Copy)]
+pub struct RenderOptions<'a> {
+    pub line_numbers: bool,
+    // Render colour escape sequences from the input, otherwise they get stripped
+    pub color: bool,
+    pub tab_width: usize,
+    // Cut long lines off at the edge of the screen instead of wrapping them
+    pub chop_long_lines: bool,
+    // Wrap at whitespace where possible rather than at exactly the edge of the screen
+    pub word_wrap: bool,
+    // Collapse runs of blank lines into one, this only affects what's shown so searches still see every line
+    pub squeeze_blank: bool,
+    // Print control chars as they are instead of as ^A, ^G and so on
+    pub raw_control_chars: bool,
+    // Show man page style X\bX as a bold X and _\bX as an underlined one
+    pub overstrike: bool,
+    // Show spaces and tabs as · and →, and make whitespace at the end of a line stand out
+    pub show_whitespace: bool,
+    // Lines to move with d and u, None for half a screen
+    pub scroll: Option<usize>,
+    // Rows from the top of the screen to put a search match or line that's jumped to, None for the middle
+    pub jump_target: Option<usize>,
+    // Rows to keep between a line that's jumped to and the top or bottom of the screen
+    pub scrolloff: usize,
+    // How many lines at the start to keep pinned to the top of the screen
+    pub header: usize,
+    // The original line number of each line when showing a filtered view, so the gutter stays meaningful
+    pub line_nos: Option<&'a [usize]>,
+    // With wrapping, a line at the top of the screen can be partly scrolled off above it. This is that line and how
+    // many of its rows are off the screen, and it only applies while that line is at the top
+    pub top_segment: (usize, usize),
+    // How many lines --max-lines has dropped from the start of the source, which line numbers still count
+    pub dropped_lines: usize,
+    // (cols, rows) of the screen. Asking the terminal is a syscall, so term_thread_fn keeps this up to date from resize
+    // events (and asks again on Ctrl-L or coming back from an editor) and everything else goes by it
+    pub size: (u16, u16),
+    // Whether the status bar and search highlights use colour, otherwise they're shown in reverse video and underlined
+    pub ui_color: bool,
+    pub theme: Theme,
+}
+
+pub struct SearchOptions {
+    // Only applies to plain searches, regexes can opt in with (?i)
+    pub case_sensitive: bool,
+    // Also only for plain searches, regexes have \b for this
+    pub whole_word: bool,
+    // Like vim's smartcase, case_sensitive is ignored and a search is only case sensitive if it has an uppercase
+    // letter in it. Toggling case sensitivity by hand turns this off
+    pub smartcase: bool,
+    // Everything searched for so far, oldest first, for recalling with Up/Down at the search prompt
+    pub history: Vec<String>,
+}
+
+#[derive(Clone, Copy)]
+struct SearchKind {
+    regex: bool,
+    // Search up from the current position, n and p are swapped to match
+    backward: bool,
+}
+
+// Kept after leaving search mode so that n/N can repeat it
+struct LastSearch {
+    query: String,
+    kind: SearchKind,
+    // The match we last jumped to, if any
+    line_no: Option<usize>,
+}
+
+// The lines matching a search, along with the search itself so the matched part of each line can be picked out
+// when it's drawn. Derefs to the line numbers since that's what most things want
+#[derive(Default)]
+struct Matches {
+    line_nos: Vec<usize>,
+    pattern: Option<regex::Regex>,
+}
+
+impl Deref for Matches {
+    type Target = [usize];
+
+    fn deref(&self) -> &[usize] {
+        &self.line_nos
+    }
+}
+
+// Set with `&`, only lines matching the query are shown
+// -s uses this too, with an empty query
+struct Filter {
+    query: String,
+    case_sensitive: bool,
+    // Only keep the first of several blank lines in a row
+    squeeze_blank: bool,
+    // Indices into the source's lines
+    line_nos: Vec<usize>,
+    // How many of the source's lines have been checked, so new lines can be filtered as they come in
+    checked: usize,
+}
+
+// The lines being shown for a source, which is only those a filter picked out of them if it has one
+#[derive(Clone, Copy)]
+struct View<'a> {
+    lines: &'a Lines,
+    // Indices into lines of the lines in the view, with a filter
+    line_nos: Option<&'a [usize]>,
+}
+
+impl<'a> View<'a> {
+    fn len(&self) -> usize {
+        self.line_nos.map_or(self.lines.len(), |line_nos| line_nos.len())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn line(&self, line_no: usize) -> Cow<'a, str> {
+        self.lines.line(self.line_nos.map_or(line_no, |line_nos| line_nos[line_no]))
+    }
+}
+
+// Settings for the session as a whole rather than for rendering
+pub struct TermOptions {
+    // Like less -F, just print the input and exit if it fits on one screen
+    pub quit_if_one_screen: bool,
+    // Scroll with the mouse wheel, at the cost of the terminal's own text selection
+    pub mouse: bool,
+    pub keymap: keymap::Keymap,
+    // 1-based, where each source was left last time, see history.rs
+    pub saved_line_nos: Vec<Option<usize>>,
+    // Lines that start a section, for [[ and ]]
+    pub section: Option<regex::Regex>,
+}
+
+// Where to open the first source, set with a `+N` or `+/pattern` argument like less
+pub enum StartPosition {
+    // 1-based, like the line numbers the user sees
+    Line(usize),
+    // The first line matching a plain search
+    Pattern(String),
+}
+
+pub fn term_thread_fn(sources: &[Arc<Source>], term_rx: mpsc::Receiver<TerminalThreadMessage>, input_tx: mpsc::Sender<InputThreadMessage>, mut options: RenderOptions, mut search_options: SearchOptions, start: Option<StartPosition>, term_options: TermOptions) -> Vec<usize> {
+    install_panic_hook();
+    options.size = terminal_size();
 
-    let mut pos_by_source = sources.iter().map(|_| Some(0)).collect::<Vec<Option<usize>>>();
+    // Sources other than the first (which goes through `start`) open where they were left last time
+    let mut pos_by_source = sources.iter().enumerate().map(|(i, _)| {
+        let line_no = term_options.saved_line_nos.get(i).copied().flatten().filter(|_| i > 0).unwrap_or(1);
+        Some(line_no.saturating_sub(1))
+    }).collect::<Vec<Option<usize>>>();
+    // Matches from the last search stay highlighted until cleared with `c`
+    let mut matches_by_source = sources.iter().map(|_| Matches::default()).collect::<Vec<Matches>>();
     let mut source_index = 0;
     let mut has_interacted = false;
+    let mut following = false;
+    let mut last_search: Option<LastSearch> = None;
+    let mut marks_by_source = sources.iter().map(|_| HashMap::new()).collect::<Vec<HashMap<char, usize>>>();
+    let mut filters_by_source = sources.iter().map(|_| None).collect::<Vec<Option<Filter>>>();
+    // Taken off the channel early while coalescing Read messages, handled before anything else
+    let mut deferred_message: Option<TerminalThreadMessage> = None;
+    // Typed before a motion key to repeat it
+    let mut count: Option<usize> = None;
+    // Shown instead of the usual source info until the first keypress
+    let mut startup_status: Option<String> = None;
+    // Where to start (i.e. +N, +/pattern, or autoscroll if there's not much input) is decided once enough has been read
+    let mut startup_pending = true;
+    let mut first_source_read = false;
+    // Whether each source can be shown, None until the user's been asked about one that looks binary
+    let mut viewable_by_source = sources.iter().map(|source| if source.binary { None } else { Some(true) }).collect::<Vec<Option<bool>>>();
+    // How many of each source's lines --max-lines had dropped as of the last look, and whether the line at the top of
+    // the screen has been dropped since the user last heard about it
+    let mut dropped_by_source = sources.iter().map(|_| 0).collect::<Vec<usize>>();
+    let mut top_dropped = false;
+    // The source and line that [[, ]], { or } last went to, so the next one carries on from there
+    let mut motion_line_no: Option<(usize, usize)> = None;
 
-    thread::sleep(Duration::from_millis(100)); // i.e. make sure there's some stuff to read on first draw
-    {
-        let (_, rows) = crossterm::terminal::size().expect("Could not get terminal size");
-        let lines = sources[source_index].lines.lock().expect("Could not take lock in term_thread");
-        if lines.len() < rows as usize { // If there aren't many lines we can start in autoscroll
-            pos_by_source[source_index] = None;
+    // Like less -F, this waits for the end of the input unless it's already clear that it won't fit
+    // With several files it's all of them together that has to fit, and they're printed one after the other like cat
+    if term_options.quit_if_one_screen {
+        // The view of all files together has no reader, it's filled in by the others
+        let mut n_unread = sources.iter().filter(|source| source.reader.is_some()).count();
+        loop {
+            match term_rx.recv() {
+                Ok(TerminalThreadMessage::Read(_)) => {
+                    if !fits_on_one_screen(sources, &options) {
+                        break;
+                    }
+                }
+                Ok(TerminalThreadMessage::Eof(read_source_index)) => {
+                    if read_source_index == 0 {
+                        first_source_read = true;
+                    }
+                    n_unread -= 1;
+                    if !fits_on_one_screen(sources, &options) {
+                        deferred_message = Some(TerminalThreadMessage::Read(read_source_index));
+                        break;
+                    }
+                    if n_unread == 0 {
+                        for source in sources.iter().filter(|source| source.reader.is_some()) {
+                            print_without_paging(&source.lines.lock().expect("Could not take lock in term_thread"), &options);
+                        }
+                        return Vec::new();
+                    }
+                }
+                Ok(TerminalThreadMessage::Resize(cols, rows)) => {
+                    options.size = usable_size(cols, rows);
+                }
+                // Keys are dropped, we're not paging yet
+                Ok(_) => {}
+                Err(_) => {
+                    return Vec::new();
+                }
+            }
         }
     }
 
+    // Not done until now so that -F can print to the normal screen
+    execute!(stdout(), EnterAlternateScreen).unwrap();
+    execute!(stdout(), DisableLineWrap).unwrap();
     enable_raw_mode().expect("Could not enter raw mode");
+    set_mouse_capture(term_options.mouse);
+
+    match next_viewable(sources, source_index, &mut viewable_by_source, &term_rx, &options) {
+        Some(i) => source_index = i,
+        None => {
+            restore_terminal();
+            return Vec::new();
+        }
+    }
+
+    // Draw straight away rather than waiting for the first read, so e.g. empty input still gets a screen
+    {
+        let lines = sources[source_index].lines.lock().expect("Could not take lock in term_thread");
+        let (lines, options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+        overwrite_last_n_lines(lines, pos_by_source[source_index], None, &matches_by_source[source_index], &options);
+    }
 
     loop {
-        if let Ok(message) = term_rx.recv() {
+        for (i, source) in sources.iter().enumerate() {
+            let dropped = source.dropped.load(Ordering::Relaxed);
+            // Less than before means the source was reloaded, which throws away everything anyway
+            if dropped > dropped_by_source[i] {
+                let dropped_top = forget_dropped_lines(dropped - dropped_by_source[i], &mut filters_by_source[i], &mut pos_by_source[i], &mut matches_by_source[i], &mut marks_by_source[i]);
+                top_dropped |= dropped_top && i == source_index;
+                // The line at the top has a new index now
+                if i == source_index {
+                    options.top_segment = (0, 0);
+                }
+            }
+            dropped_by_source[i] = dropped;
+        }
+        options.dropped_lines = dropped_by_source[source_index];
+
+        // A watched file that hasn't been written to yet would otherwise just be a blank screen
+        if sources[source_index].watched && sources[source_index].lines.lock().expect("Could not take lock in term_thread").is_empty() {
+            write_status_message(&format!("Waiting for data in \"{}\"...", sources[source_index].name), &options);
+        }
+
+        if let Some(message) = deferred_message.take().or_else(|| term_rx.recv().ok()) {
             match message {
                 TerminalThreadMessage::KeyEvent(event) => {
                     if event.kind != KeyEventKind::Press {
                         continue;
                     }
+                    let event = keymap::translate(&term_options.keymap, event);
+
+                    // Digits build up a count for the next key, like vim's 10j or 50G
+                    if let crossterm::event::KeyCode::Char(c @ '0'..='9') = event.code {
+                        let digit = c.to_digit(10).unwrap() as usize;
+                        count = Some(count.unwrap_or(0).saturating_mul(10).saturating_add(digit));
+                        continue;
+                    }
+                    // Any other key uses up the count, whether or not it cares about it
+                    let count = count.take();
 
                     match event.code {
                         crossterm::event::KeyCode::Char('q') | crossterm::event::KeyCode::Char('Q') | crossterm::event::KeyCode::Esc => {
                             break;
                         }
-                        crossterm::event::KeyCode::Up => {
+                        crossterm::event::KeyCode::Char('{') | crossterm::event::KeyCode::Char('}') | crossterm::event::KeyCode::Up | crossterm::event::KeyCode::Down
+                            if matches!(event.code, crossterm::event::KeyCode::Char(_)) || event.modifiers.contains(KeyModifiers::CONTROL) => {
+                            let backward = matches!(event.code, crossterm::event::KeyCode::Char('{') | crossterm::event::KeyCode::Up);
+                            let lines = sources[source_index].lines.lock().expect("Could not take lock in blank line event handler");
+                            let (lines, view_options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                            let from = motion_start(motion_line_no, source_index, pos_by_source[source_index], lines.len(), &options);
+                            let target = (0..count.unwrap_or(1)).try_fold(from, |line_no, _| next_blank_line(lines, line_no, backward));
+                            match target {
+                                Some(line_no) => {
+                                    following = false;
+                                    motion_line_no = Some((source_index, line_no));
+                                    pos_by_source[source_index] = pos_with_in_view(Some(line_no), options.scrolloff);
+                                    options.top_segment = (0, 0);
+                                    overwrite_last_n_lines(lines, pos_by_source[source_index], None, &matches_by_source[source_index], &RenderOptions { top_segment: (0, 0), ..view_options });
+                                }
+                                None => {
+                                    write_status_message("No blank line", &options);
+                                }
+                            }
+                        }
+                        crossterm::event::KeyCode::Up | crossterm::event::KeyCode::Char('k') | crossterm::event::KeyCode::Char('K') => {
+                            following = false;
                             {
                                 let lines = sources[source_index].lines.lock().expect("Could not take lock in ArrrowUp event handler");
-                                page_by(&lines, &mut pos_by_source[source_index], -1);
+                                let (lines, view_options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                                options.top_segment = scroll_rows(lines, &mut pos_by_source[source_index], -count_offset(count), &matches_by_source[source_index], &view_options);
+                            }
+                        }
+                        crossterm::event::KeyCode::Char('u') if event.modifiers.contains(KeyModifiers::CONTROL) => {
+                            following = false;
+                            // Like less, a count becomes the new scroll size for d and u
+                            if count.is_some() {
+                                options.scroll = count;
+                            }
+                            options.top_segment = (0, 0);
+                            {
+                                let lines = sources[source_index].lines.lock().expect("Could not take lock in Ctrl-U event handler");
+                                let (lines, options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                                page_by(lines, &mut pos_by_source[source_index], -scroll_size(&options), &matches_by_source[source_index], &options);
                             }
                         }
                         crossterm::event::KeyCode::Char('u') | crossterm::event::KeyCode::Char('U') | crossterm::event::KeyCode::PageUp => {
+                            following = false;
+                            // Like less, a count becomes the new scroll size for d and u
+                            if count.is_some() {
+                                options.scroll = count;
+                            }
+                            options.top_segment = (0, 0);
                             {
                                 let lines = sources[source_index].lines.lock().expect("Could not take lock in PgUp event handler");
-                                page_by(&lines, &mut pos_by_source[source_index], -(PAGE_UP_SIZE as i32));
+                                let (lines, options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                                page_by(lines, &mut pos_by_source[source_index], -scroll_size(&options), &matches_by_source[source_index], &options);
                             }
                         }
-                        crossterm::event::KeyCode::Down => {
+                        crossterm::event::KeyCode::Down | crossterm::event::KeyCode::Char('j') | crossterm::event::KeyCode::Char('J') => {
                             {
                                 let lines = sources[source_index].lines.lock().expect("Could not take lock in ArrowDown event handler");
-                                page_by(&lines, &mut pos_by_source[source_index], 1);
+                                let (lines, view_options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                                options.top_segment = scroll_rows(lines, &mut pos_by_source[source_index], count_offset(count), &matches_by_source[source_index], &view_options);
+                            }
+                        }
+                        crossterm::event::KeyCode::Char('d') if event.modifiers.contains(KeyModifiers::CONTROL) => {
+                            // Like less, a count becomes the new scroll size for d and u
+                            if count.is_some() {
+                                options.scroll = count;
+                            }
+                            options.top_segment = (0, 0);
+                            {
+                                let lines = sources[source_index].lines.lock().expect("Could not take lock in Ctrl-D event handler");
+                                let (lines, options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                                page_by(lines, &mut pos_by_source[source_index], scroll_size(&options), &matches_by_source[source_index], &options);
+                            }
+                        }
+                        crossterm::event::KeyCode::Char(' ') => {
+                            options.top_segment = (0, 0);
+                            {
+                                let lines = sources[source_index].lines.lock().expect("Could not take lock in Space event handler");
+                                let (lines, options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                                page_by(lines, &mut pos_by_source[source_index], full_page_size(&options), &matches_by_source[source_index], &options);
+                            }
+                        }
+                        crossterm::event::KeyCode::Char('b') | crossterm::event::KeyCode::Char('B') => {
+                            following = false;
+                            options.top_segment = (0, 0);
+                            {
+                                let lines = sources[source_index].lines.lock().expect("Could not take lock in page back event handler");
+                                let (lines, options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                                page_by(lines, &mut pos_by_source[source_index], -full_page_size(&options), &matches_by_source[source_index], &options);
                             }
                         }
-                        crossterm::event::KeyCode::Char('d') | crossterm::event::KeyCode::Char('D') | crossterm::event::KeyCode::PageDown | crossterm::event::KeyCode::Char(' ') => {
+                        crossterm::event::KeyCode::Char('d') | crossterm::event::KeyCode::Char('D') | crossterm::event::KeyCode::PageDown => {
+                            // Like less, a count becomes the new scroll size for d and u
+                            if count.is_some() {
+                                options.scroll = count;
+                            }
+                            options.top_segment = (0, 0);
                             {
                                 let lines = sources[source_index].lines.lock().expect("Could not take lock in PgDn event handler");
-                                page_by(&lines, &mut pos_by_source[source_index], PAGE_UP_SIZE as i32);
+                                let (lines, options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                                page_by(lines, &mut pos_by_source[source_index], scroll_size(&options), &matches_by_source[source_index], &options);
                             }
                         }
                         crossterm::event::KeyCode::Enter => {
                             pos_by_source[source_index] = None;
+                            options.top_segment = (0, 0);
                             {
                                 let lines = sources[source_index].lines.lock().expect("Could not take lock in Enter event handler");
-                                overwrite_last_n_lines(&lines, pos_by_source[source_index], None);
+                                let (lines, options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                                overwrite_last_n_lines(lines, pos_by_source[source_index], None, &matches_by_source[source_index], &options);
+                            }
+                        }
+                        crossterm::event::KeyCode::Home => {
+                            following = false;
+                            pos_by_source[source_index] = Some(0);
+                            options.top_segment = (0, 0);
+                            {
+                                let lines = sources[source_index].lines.lock().expect("Could not take lock in Home event handler");
+                                let (lines, options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                                overwrite_last_n_lines(lines, pos_by_source[source_index], None, &matches_by_source[source_index], &options);
+                            }
+                        }
+                        crossterm::event::KeyCode::End => {
+                            pos_by_source[source_index] = None;
+                            options.top_segment = (0, 0);
+                            {
+                                let lines = sources[source_index].lines.lock().expect("Could not take lock in End event handler");
+                                let (lines, options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                                overwrite_last_n_lines(lines, pos_by_source[source_index], None, &matches_by_source[source_index], &options);
                             }
                         }
+                        crossterm::event::KeyCode::Char('g') | crossterm::event::KeyCode::Char('=') if event.code == crossterm::event::KeyCode::Char('=') || event.modifiers.contains(KeyModifiers::CONTROL) => {
+                            let lines = sources[source_index].lines.lock().expect("Could not take lock in file info event handler");
+                            let (lines, options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                            write_status_message(&file_info(&sources[source_index], lines, pos_by_source[source_index], &options), &options);
+                        }
                         crossterm::event::KeyCode::Char('g') | crossterm::event::KeyCode::Char('G') => {
+                            following = false;
                             let mut highlight_line_no = None;
+                            let mut go_to_line_status = None;
+                            options.top_segment = (0, 0);
                             {
                                 let lines = sources[source_index].lines.lock().expect("Could not take lock in goto line event handler");
-                                if event.modifiers.contains(KeyModifiers::SHIFT) {
+                                let (lines, options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                                if let Some(line_no) = count {
+                                    // A count goes straight to that line, like 50G
+                                    let line_no = view_line_no(line_no.saturating_sub(1), &options).min(lines.len().saturating_sub(1));
+                                    highlight_line_no = Some(line_no);
+                                    pos_by_source[source_index] = pos_with_in_view(Some(line_no), jump_offset(&options));
+                                } else if event.modifiers.contains(KeyModifiers::SHIFT) {
                                     pos_by_source[source_index] = None;
                                 } else {
-                                    let line_no: Option<usize> = handle_go_to_line(pos_by_source[source_index], lines.len(), &term_rx);
+                                    let (line_no, status) = handle_go_to_line(pos_by_source[source_index], original_line_count(lines, &options), &term_rx, &options);
+                                    go_to_line_status = status;
+                                    let line_no = line_no.map(|n| view_line_no(n, &options).min(lines.len().saturating_sub(1)));
                                     highlight_line_no = line_no;
-                                    pos_by_source[source_index] = pos_with_in_view(line_no, PAGE_UP_SIZE);
+                                    pos_by_source[source_index] = pos_with_in_view(line_no, jump_offset(&options));
                                 }
-                                overwrite_last_n_lines(&lines, pos_by_source[source_index], highlight_line_no);
+                                overwrite_last_n_lines(lines, pos_by_source[source_index], highlight_line_no, &matches_by_source[source_index], &options);
+                            }
+                            if let Some(status) = go_to_line_status {
+                                write_status_message(&status, &options);
+                            }
+                        }
+                        crossterm::event::KeyCode::Char('%') => {
+                            following = false;
+                            let percent = count.unwrap_or(0).min(100);
+                            options.top_segment = (0, 0);
+                            {
+                                let lines = sources[source_index].lines.lock().expect("Could not take lock in percent event handler");
+                                let (lines, options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                                pos_by_source[source_index] = clamp_pos(Some(percent * lines.len() / 100), lines.len());
+                                overwrite_last_n_lines(lines, pos_by_source[source_index], None, &matches_by_source[source_index], &options);
                             }
+                            write_status_message(&format!("at {}%", percent), &options);
                         }
                         crossterm::event::KeyCode::Char('/') => {
-                            handle_search_mode(&mut pos_by_source[source_index], &sources[source_index].lines, &term_rx, PAGE_UP_SIZE, false);
+                            options.top_segment = (0, 0);
+                            let (lines, line_nos) = search_snapshot(&sources[source_index], &mut filters_by_source[source_index], &options);
+                            let search_view_options = RenderOptions { line_nos: line_nos.as_deref(), ..options };
+                            let lines = View { lines: &lines, line_nos: line_nos.as_deref() };
+                            if let Some(search) = handle_search_mode(&mut pos_by_source[source_index], lines, &term_rx, SearchKind { regex: false, backward: false }, &mut search_options, &mut matches_by_source[source_index], &search_view_options) {
+                                write_lines_since_search(&search, &matches_by_source[source_index], lines.len(), &sources[source_index], &mut filters_by_source[source_index], &options, &search_view_options);
+                                last_search = Some(search);
+                            }
+                        }
+                        crossterm::event::KeyCode::Char('?') => {
+                            options.top_segment = (0, 0);
+                            let (lines, line_nos) = search_snapshot(&sources[source_index], &mut filters_by_source[source_index], &options);
+                            let search_view_options = RenderOptions { line_nos: line_nos.as_deref(), ..options };
+                            let lines = View { lines: &lines, line_nos: line_nos.as_deref() };
+                            if let Some(search) = handle_search_mode(&mut pos_by_source[source_index], lines, &term_rx, SearchKind { regex: false, backward: true }, &mut search_options, &mut matches_by_source[source_index], &search_view_options) {
+                                write_lines_since_search(&search, &matches_by_source[source_index], lines.len(), &sources[source_index], &mut filters_by_source[source_index], &options, &search_view_options);
+                                last_search = Some(search);
+                            }
                         }
                         crossterm::event::KeyCode::Char('r') | crossterm::event::KeyCode::Char('R') => {
-                            handle_search_mode(&mut pos_by_source[source_index], &sources[source_index].lines, &term_rx, PAGE_UP_SIZE, true);
+                            options.top_segment = (0, 0);
+                            let (lines, line_nos) = search_snapshot(&sources[source_index], &mut filters_by_source[source_index], &options);
+                            let search_view_options = RenderOptions { line_nos: line_nos.as_deref(), ..options };
+                            let lines = View { lines: &lines, line_nos: line_nos.as_deref() };
+                            if let Some(search) = handle_search_mode(&mut pos_by_source[source_index], lines, &term_rx, SearchKind { regex: true, backward: false }, &mut search_options, &mut matches_by_source[source_index], &search_view_options) {
+                                write_lines_since_search(&search, &matches_by_source[source_index], lines.len(), &sources[source_index], &mut filters_by_source[source_index], &options, &search_view_options);
+                                last_search = Some(search);
+                            }
                         },
+                        crossterm::event::KeyCode::Char('n') | crossterm::event::KeyCode::Char('N') => {
+                            let lines = sources[source_index].lines.lock().expect("Could not take lock in repeat search event handler");
+                            let (lines, view_options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                            match last_search.as_mut() {
+                                Some(search) => {
+                                    // n goes in the direction of the original search, N the other way
+                                    let backward = search.kind.backward != (event.code == crossterm::event::KeyCode::Char('N'));
+                                    options.top_segment = (0, 0);
+                                    matches_by_source[source_index] = get_matches(lines, &search.query, search.kind.regex, is_case_sensitive(&search.query, &search_options), search_options.whole_word);
+                                    search.line_no = repeat_search(lines, &matches_by_source[source_index], &mut pos_by_source[source_index], search.line_no, backward, &RenderOptions { top_segment: (0, 0), ..view_options });
+                                }
+                                None => {
+                                    write_status_message("No previous search", &options);
+                                }
+                            }
+                        }
+                        crossterm::event::KeyCode::Char('l') if event.modifiers.contains(KeyModifiers::CONTROL) => {
+                            // The size is asked for again too in case a resize event got lost
+                            options.size = terminal_size();
+                            {
+                                let lines = sources[source_index].lines.lock().expect("Could not take lock in Ctrl-L event handler");
+                                let (lines, options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                                overwrite_last_n_lines(lines, pos_by_source[source_index], None, &matches_by_source[source_index], &options);
+                            }
+                        }
+                        crossterm::event::KeyCode::Char('l') | crossterm::event::KeyCode::Char('L') => {
+                            options.line_numbers = !options.line_numbers;
+                            {
+                                let lines = sources[source_index].lines.lock().expect("Could not take lock in line number toggle event handler");
+                                let (lines, options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                                overwrite_last_n_lines(lines, pos_by_source[source_index], None, &matches_by_source[source_index], &options);
+                            }
+                        }
+                        crossterm::event::KeyCode::Char('t') | crossterm::event::KeyCode::Char('T') => {
+                            // A count pins that many lines, otherwise this turns the header off, or on with one line
+                            options.header = match count {
+                                Some(n) => n,
+                                None if options.header > 0 => 0,
+                                None => 1,
+                            };
+                            {
+                                let lines = sources[source_index].lines.lock().expect("Could not take lock in header event handler");
+                                let (lines, options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                                overwrite_last_n_lines(lines, pos_by_source[source_index], None, &matches_by_source[source_index], &options);
+                            }
+                            match options.header {
+                                0 => write_status_message("Header off", &options),
+                                n => write_status_message(&format!("Header: first {} line{} pinned", n, if n == 1 { "" } else { "s" }), &options),
+                            }
+                        }
+                        crossterm::event::KeyCode::Char('x') | crossterm::event::KeyCode::Char('X') => {
+                            options.chop_long_lines = !options.chop_long_lines;
+                            {
+                                let lines = sources[source_index].lines.lock().expect("Could not take lock in chop toggle event handler");
+                                let (lines, options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                                overwrite_last_n_lines(lines, pos_by_source[source_index], None, &matches_by_source[source_index], &options);
+                            }
+                        }
+                        crossterm::event::KeyCode::Char('w') | crossterm::event::KeyCode::Char('W') => {
+                            options.word_wrap = !options.word_wrap;
+                            {
+                                let lines = sources[source_index].lines.lock().expect("Could not take lock in word wrap toggle event handler");
+                                let (lines, options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                                overwrite_last_n_lines(lines, pos_by_source[source_index], None, &matches_by_source[source_index], &options);
+                            }
+                        }
+                        crossterm::event::KeyCode::Char('i') | crossterm::event::KeyCode::Char('I') => {
+                            options.show_whitespace = !options.show_whitespace;
+                            {
+                                let lines = sources[source_index].lines.lock().expect("Could not take lock in whitespace toggle event handler");
+                                let (lines, options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                                overwrite_last_n_lines(lines, pos_by_source[source_index], None, &matches_by_source[source_index], &options);
+                            }
+                        }
+                        crossterm::event::KeyCode::Char('m') | crossterm::event::KeyCode::Char('M') => {
+                            let lines = sources[source_index].lines.lock().expect("Could not take lock in set mark event handler");
+                            let (lines, options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                            match read_mark_name("Set mark: ", &term_rx, &options) {
+                                Some(name) => {
+                                    let top = screen_top(pos_by_source[source_index], lines.len(), &options);
+                                    marks_by_source[source_index].insert(name, top);
+                                    // Marks are kept as lines in the view, but the user knows lines by their number in the file
+                                    let line_no = if top < lines.len() { original_line_no(top, &options) } else { options.dropped_lines };
+                                    write_status_message(&format!("Mark '{}' set at line {}", name, line_no + 1), &options);
+                                }
+                                None => {
+                                    overwrite_last_n_lines(lines, pos_by_source[source_index], None, &matches_by_source[source_index], &options);
+                                }
+                            }
+                        }
+                        crossterm::event::KeyCode::Char('\'') => {
+                            let lines = sources[source_index].lines.lock().expect("Could not take lock in go to mark event handler");
+                            let (lines, view_options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                            match read_mark_name("Go to mark: ", &term_rx, &options) {
+                                Some(name) => {
+                                    match marks_by_source[source_index].get(&name) {
+                                        Some(&line_no) => {
+                                            following = false;
+                                            pos_by_source[source_index] = Some(line_no);
+                                            options.top_segment = (0, 0);
+                                            overwrite_last_n_lines(lines, pos_by_source[source_index], None, &matches_by_source[source_index], &RenderOptions { top_segment: (0, 0), ..view_options });
+                                        }
+                                        None => {
+                                            write_status_message(&format!("Mark '{}' not set", name), &options);
+                                        }
+                                    }
+                                }
+                                None => {
+                                    overwrite_last_n_lines(lines, pos_by_source[source_index], None, &matches_by_source[source_index], &view_options);
+                                }
+                            }
+                        }
+                        crossterm::event::KeyCode::Char('v') | crossterm::event::KeyCode::Char('V') => {
+                            let lines = sources[source_index].lines.lock().expect("Could not take lock in edit event handler");
+                            let (lines, view_options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                            match &sources[source_index].path {
+                                Some(path) => {
+                                    let (_, rows) = options.size;
+                                    let line_no = (screen_top(pos_by_source[source_index], lines.len(), &options) + rows as usize / 2).min(lines.len().saturating_sub(1));
+                                    // The editor wants the line in the file, which a filter, -s or --max-lines can put elsewhere
+                                    let line_no = if line_no < lines.len() { original_line_no(line_no, &view_options) } else { options.dropped_lines };
+                                    let result = open_in_editor(path, line_no + 1, &input_tx);
+                                    options.size = terminal_size();
+                                    let view_options = RenderOptions { size: options.size, ..view_options };
+                                    overwrite_last_n_lines(lines, pos_by_source[source_index], None, &matches_by_source[source_index], &view_options);
+                                    if let Err(e) = result {
+                                        write_status_message(&format!("Could not run editor: {}", e), &options);
+                                    }
+                                }
+                                None => {
+                                    write_status_message("Cannot edit: no file", &options);
+                                }
+                            }
+                        }
+                        crossterm::event::KeyCode::Char('|') => {
+                            let lines = sources[source_index].lines.lock().expect("Could not take lock in pipe event handler");
+                            let (lines, view_options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                            let matches = &matches_by_source[source_index];
+                            match read_prompt("Pipe to", &term_rx, &options) {
+                                Some(command) if !command.is_empty() => {
+                                    // The highlighted matches if there are any, otherwise everything
+                                    let result = if matches.is_empty() {
+                                        pipe_to_command(&command, (0..lines.len()).map(|line_no| lines.line(line_no)), &input_tx)
+                                    } else {
+                                        pipe_to_command(&command, matches.iter().map(|&line_no| lines.line(line_no)), &input_tx)
+                                    };
+                                    options.size = terminal_size();
+                                    let view_options = RenderOptions { size: options.size, ..view_options };
+                                    overwrite_last_n_lines(lines, pos_by_source[source_index], None, matches, &view_options);
+                                    match result {
+                                        Ok(status) if !status.success() => write_status_message(&format!("{} exited ({})", command, status), &options),
+                                        Ok(_) => {}
+                                        Err(e) => write_status_message(&format!("Could not run {}: {}", command, e), &options),
+                                    }
+                                }
+                                _ => overwrite_last_n_lines(lines, pos_by_source[source_index], None, matches, &view_options),
+                            }
+                        }
+                        crossterm::event::KeyCode::Char('&') => {
+                            let lines = sources[source_index].lines.lock().expect("Could not take lock in filter event handler");
+                            // An empty query removes the filter
+                            if let Some(query) = read_prompt("Filter", &term_rx, &options) {
+                                // Keep the line at the top of the screen in view, as best we can
+                                let top = {
+                                    let (view, view_options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                                    let top = screen_top(pos_by_source[source_index], view.len(), &options);
+                                    if top < view.len() { original_line_no(top, &view_options) } else { 0 }
+                                };
+
+                                options.top_segment = (0, 0);
+                                filters_by_source[source_index] = if query.is_empty() {
+                                    None
+                                } else {
+                                    Some(Filter { case_sensitive: is_case_sensitive(&query, &search_options), query, squeeze_blank: options.squeeze_blank, line_nos: Vec::new(), checked: 0 })
+                                };
+                                if pos_by_source[source_index].is_some() {
+                                    let (view, view_options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                                    let view_top = view_line_no(top, &view_options);
+                                    pos_by_source[source_index] = clamp_pos(Some(view_top), view.len());
+                                }
+
+                                // Matches and marks are line numbers in the view, which just changed underneath them
+                                matches_by_source[source_index] = Matches::default();
+                                marks_by_source[source_index].clear();
+                                if let Some(search) = last_search.as_mut() {
+                                    search.line_no = None;
+                                }
+                            }
+
+                            {
+                                let (view, view_options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                                overwrite_last_n_lines(view, pos_by_source[source_index], None, &matches_by_source[source_index], &view_options);
+                            }
+                            if let Some(filter) = filters_by_source[source_index].as_ref().filter(|filter| !filter.query.is_empty()) {
+                                write_status_message(&format!("filter: {} ({} lines)", filter.query, filter.line_nos.len()), &options);
+                            }
+                        }
+                        crossterm::event::KeyCode::Char('h') | crossterm::event::KeyCode::Char('H') => {
+                            show_help(&term_rx, &mut options);
+                            {
+                                let lines = sources[source_index].lines.lock().expect("Could not take lock in help event handler");
+                                let (lines, options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                                overwrite_last_n_lines(lines, pos_by_source[source_index], None, &matches_by_source[source_index], &options);
+                            }
+                        }
+                        crossterm::event::KeyCode::Char('c') | crossterm::event::KeyCode::Char('C') => {
+                            matches_by_source[source_index] = Matches::default();
+                            {
+                                let lines = sources[source_index].lines.lock().expect("Could not take lock in clear highlight event handler");
+                                let (lines, options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                                overwrite_last_n_lines(lines, pos_by_source[source_index], None, &matches_by_source[source_index], &options);
+                            }
+                        }
+                        crossterm::event::KeyCode::Char('z') | crossterm::event::KeyCode::Char('Z') => {
+                            let mouse = !MOUSE_CAPTURE.load(Ordering::Relaxed);
+                            set_mouse_capture(mouse);
+                            write_status_message(if mouse { "Mouse wheel scrolling on" } else { "Mouse wheel scrolling off, text can be selected" }, &options);
+                        }
+                        crossterm::event::KeyCode::Char('f') | crossterm::event::KeyCode::Char('F') => {
+                            following = !following;
+                            if following {
+                                pos_by_source[source_index] = None;
+                            }
+                            options.top_segment = (0, 0);
+                            {
+                                let lines = sources[source_index].lines.lock().expect("Could not take lock in follow toggle event handler");
+                                let (lines, options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                                overwrite_last_n_lines(lines, pos_by_source[source_index], None, &matches_by_source[source_index], &options);
+                            }
+                            if following {
+                                write_status_message("Following...", &options);
+                            }
+                        }
                         crossterm::event::KeyCode::Char('s') | crossterm::event::KeyCode::Char('S') => {
-                            source_index += 1;
-                            source_index %= sources.len();
+                            source_index = next_viewable(sources, (source_index + 1) % sources.len(), &mut viewable_by_source, &term_rx, &options).unwrap_or(source_index);
+                            options.dropped_lines = dropped_by_source[source_index];
+                            options.top_segment = (0, 0);
 
                             {
                                 let lines = sources[source_index].lines.lock().expect("Could not take lock in source switch event handler");
-                                overwrite_last_n_lines(&lines, pos_by_source[source_index], None);
-                                write_status_message(format!("Switched to source: {}", sources[source_index].name).as_str());
+                                let n_lines = lines.len();
+                                let (lines, options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                                pos_by_source[source_index] = clamp_pos(pos_by_source[source_index], lines.len());
+                                overwrite_last_n_lines(lines, pos_by_source[source_index], None, &matches_by_source[source_index], &options);
+                                write_status_message(&switched_status(sources, source_index, n_lines), &options);
+                            }
+                        }
+                        crossterm::event::KeyCode::Char('o') | crossterm::event::KeyCode::Char('O') => {
+                            let message = match pick_source(sources, source_index, &term_rx, &mut options) {
+                                Some(picked) if !is_viewable(sources, picked, &mut viewable_by_source, &term_rx, &options) => {
+                                    Some(format!("Skipped {}", sources[picked].name))
+                                }
+                                Some(picked) => {
+                                    source_index = picked;
+                                    options.dropped_lines = dropped_by_source[source_index];
+                                    options.top_segment = (0, 0);
+                                    let n_lines = sources[source_index].lines.lock().expect("Could not take lock in source pick event handler").len();
+                                    Some(switched_status(sources, source_index, n_lines))
+                                }
+                                None => None,
+                            };
+
+                            {
+                                let lines = sources[source_index].lines.lock().expect("Could not take lock in source pick event handler");
+                                let (lines, options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                                pos_by_source[source_index] = clamp_pos(pos_by_source[source_index], lines.len());
+                                overwrite_last_n_lines(lines, pos_by_source[source_index], None, &matches_by_source[source_index], &options);
+                            }
+                            if let Some(message) = message {
+                                write_status_message(&message, &options);
+                            }
+                        }
+                        crossterm::event::KeyCode::Char(']') | crossterm::event::KeyCode::Char('[') => {
+                            // With --section, [[ and ]] move between sections. [ or ] on its own still switches files,
+                            // once nothing else has come along for a moment, and anything else that comes is handled as usual
+                            let next_message = term_options.section.as_ref().and_then(|_| next_message_within(&term_rx, SECOND_KEY_TIMEOUT));
+                            match (&term_options.section, next_message) {
+                                (Some(section), Some(TerminalThreadMessage::KeyEvent(key))) if key.code == event.code => {
+                                    let backward = event.code == crossterm::event::KeyCode::Char('[');
+                                    let lines = sources[source_index].lines.lock().expect("Could not take lock in section event handler");
+                                    let (lines, options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                                    let from = motion_start(motion_line_no, source_index, pos_by_source[source_index], lines.len(), &options);
+                                    match next_matching_line(lines, section, from, backward) {
+                                        Some(line_no) => {
+                                            following = false;
+                                            motion_line_no = Some((source_index, line_no));
+                                            pos_by_source[source_index] = pos_with_in_view(Some(line_no), jump_offset(&options));
+                                            overwrite_last_n_lines(lines, pos_by_source[source_index], Some(line_no), &matches_by_source[source_index], &options);
+                                        }
+                                        None => {
+                                            write_status_message(if backward { "No previous section" } else { "No next section" }, &options);
+                                        }
+                                    }
+                                }
+                                (_, next_message) => {
+                                    deferred_message = next_message;
+                                    // Unlike s this only visits sources backed by real files
+                                    let file_indices = (0..sources.len()).filter(|&i| sources[i].path.is_some() && viewable_by_source[i] != Some(false)).collect::<Vec<usize>>();
+                                    let current = file_indices.iter().position(|&i| i == source_index);
+                                    let next = match (current, event.code == crossterm::event::KeyCode::Char(']')) {
+                                        _ if file_indices.is_empty() => None,
+                                        (Some(n), true) => Some((n + 1) % file_indices.len()),
+                                        (Some(n), false) => Some((n + file_indices.len() - 1) % file_indices.len()),
+                                        (None, true) => Some(0),
+                                        (None, false) => Some(file_indices.len() - 1),
+                                    };
+
+                                    match next {
+                                        Some(n) if !is_viewable(sources, file_indices[n], &mut viewable_by_source, &term_rx, &options) => {
+                                            write_status_message(&format!("Skipped {}", sources[file_indices[n]].name), &options);
+                                        }
+                                        Some(n) => {
+                                            source_index = file_indices[n];
+                                            options.dropped_lines = dropped_by_source[source_index];
+                                            options.top_segment = (0, 0);
+                                            let lines = sources[source_index].lines.lock().expect("Could not take lock in file switch event handler");
+                                            let (lines, options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                                            pos_by_source[source_index] = clamp_pos(pos_by_source[source_index], lines.len());
+                                            overwrite_last_n_lines(lines, pos_by_source[source_index], None, &matches_by_source[source_index], &options);
+                                            write_status_message(&format!("file {}/{}: {}", n + 1, file_indices.len(), sources[source_index].name), &options);
+                                        }
+                                        None => {
+                                            write_status_message("No files to switch to", &options);
+                                        }
+                                    }
+                                }
                             }
                         }
                         _ => {}
@@ -109,213 +893,1018 @@ pub fn term_thread_fn(sources: &[Arc<Source>], term_rx: mpsc::Receiver<TerminalT
 
                     has_interacted = true;
                 },
-                TerminalThreadMessage::Resize(_, _) => {
+                TerminalThreadMessage::Scroll(n_lines) => {
+                    if n_lines < 0 {
+                        following = false;
+                    }
+                    let lines = sources[source_index].lines.lock().expect("Could not take lock in mouse scroll event handler");
+                    let (lines, view_options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                    options.top_segment = scroll_rows(lines, &mut pos_by_source[source_index], n_lines, &matches_by_source[source_index], &view_options);
+                    has_interacted = true;
+                }
+                TerminalThreadMessage::Resize(cols, rows) => {
+                    options.size = usable_size(cols, rows);
                     let lines = sources[source_index].lines.lock().expect("Could not take lock in resize event handler");
-                    overwrite_last_n_lines(&lines, pos_by_source[source_index], None);
+                    let (lines, options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                    overwrite_last_n_lines(lines, pos_by_source[source_index], None, &matches_by_source[source_index], &options);
+
+                    if following {
+                        write_status_message("Following...", &options);
+                    }
                 }
-                TerminalThreadMessage::Read => {
+                TerminalThreadMessage::Read(read_source_index) => {
+                    // Readers send one of these per line, redrawing for each would mean thousands of redraws for a
+                    // big file, so catch up on all of them at once. Anything else is kept for the next go round
+                    let mut current_source_changed = read_source_index == source_index;
+                    loop {
+                        match term_rx.try_recv() {
+                            Ok(TerminalThreadMessage::Read(read_source_index)) => {
+                                current_source_changed |= read_source_index == source_index;
+                            }
+                            Ok(message) => {
+                                deferred_message = Some(message);
+                                break;
+                            }
+                            Err(_) => {
+                                break;
+                            }
+                        }
+                    }
+
+                    // Other sources are drawn when they're switched to
+                    if !current_source_changed {
+                        continue;
+                    }
+
+                    // Once the user has moved, they've picked where they want to be
+                    if startup_pending && !has_interacted && source_index == 0 {
+                        let (_, rows) = options.size;
+                        let lines = sources[0].lines.lock().expect("Could not take lock in read event handler");
+                        let (lines, options) = filtered_view(&lines, &mut filters_by_source[0], &options);
+                        match &start {
+                            Some(StartPosition::Line(line_no)) => {
+                                if lines.len() >= *line_no || first_source_read {
+                                    pos_by_source[0] = clamp_pos(Some(view_line_no(line_no.saturating_sub(1), &options)), lines.len());
+                                    startup_pending = false;
+                                }
+                            }
+                            Some(StartPosition::Pattern(query)) => {
+                                let matches = get_matches(lines, query, false, is_case_sensitive(query, &search_options), search_options.whole_word);
+                                if !matches.is_empty() || first_source_read {
+                                    match matches.first() {
+                                        Some(&line_no) => {
+                                            pos_by_source[0] = pos_with_in_view(Some(line_no), jump_offset(&options));
+                                            startup_status = Some(match_status(&matches, 0, &options));
+                                        }
+                                        None => {
+                                            startup_status = Some("Pattern not found".to_string());
+                                        }
+                                    }
+                                    last_search = Some(LastSearch { query: query.clone(), kind: SearchKind { regex: false, backward: false }, line_no: matches.first().copied() });
+                                    matches_by_source[0] = matches;
+                                    startup_pending = false;
+                                }
+                            }
+                            None => {
+                                // If there aren't many lines we can start in autoscroll. Files can only be called short
+                                // once they've been read to the end, anything else is decided on the first read
+                                if sources[0].path.is_none() || first_source_read {
+                                    if lines.len() < rows as usize {
+                                        pos_by_source[0] = None;
+                                    }
+                                    startup_pending = false;
+                                }
+                            }
+                        }
+                    }
+
+                    if following {
+                        pos_by_source[source_index] = None;
+                    }
+
                     let lines = sources[source_index].lines.lock().expect("Could not take lock in read event handler");
-                    overwrite_last_n_lines(&lines, pos_by_source[source_index], None);
+                    let (lines, options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                    overwrite_last_n_lines(lines, pos_by_source[source_index], None, &matches_by_source[source_index], &options);
+
+                    if following {
+                        write_status_message("Following...", &options);
+                    } else if top_dropped {
+                        write_status_message(&format!("Earliest {} lines dropped", options.dropped_lines), &options);
+                        top_dropped = false;
+                    } else if !has_interacted {
+                        match &startup_status {
+                            Some(status) => write_status_message(status, &options),
+                            None => write_status_message(format!("Viewing source: {}, {} sources loaded", sources[source_index].name, sources.len()).as_str(), &options),
+                        }
+                    }
+                }
+                TerminalThreadMessage::Eof(read_source_index) => {
+                    if read_source_index == 0 {
+                        first_source_read = true;
+                    }
+                    // Anything waiting on the end of the input gets handled along with the usual redraw
+                    deferred_message = Some(TerminalThreadMessage::Read(read_source_index));
+                }
+                TerminalThreadMessage::Reloaded { source_index: reloaded_index, old_len, new_len, message } => {
+                    // Anything worked out from the old lines is stale
+                    if let Some(filter) = filters_by_source[reloaded_index].as_mut() {
+                        filter.line_nos.clear();
+                        filter.checked = 0;
+                    }
+                    matches_by_source[reloaded_index] = Matches::default();
+
+                    // Stay put if the new contents are about as long as the old (e.g. a command re-run with --interval),
+                    // otherwise start again from the top
+                    let similar = new_len.is_some_and(|new_len| new_len.abs_diff(old_len) <= old_len / 10);
+                    if !similar && pos_by_source[reloaded_index].is_some() {
+                        pos_by_source[reloaded_index] = Some(0);
+                    }
+                    if reloaded_index == source_index {
+                        options.top_segment = (0, 0);
+                    }
 
-                    if !has_interacted {
-                        write_status_message(format!("Viewing source: {}, {} sources loaded", sources[source_index].name, sources.len()).as_str());
+                    if reloaded_index == source_index {
+                        let lines = sources[source_index].lines.lock().expect("Could not take lock in reload event handler");
+                        let (lines, options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                        overwrite_last_n_lines(lines, pos_by_source[source_index], None, &matches_by_source[source_index], &options);
+                        write_status_message(&message, &options);
                     }
                 }
+                TerminalThreadMessage::Status(message) => {
+                    let lines = sources[source_index].lines.lock().expect("Could not take lock in status event handler");
+                    let (lines, options) = filtered_view(&lines, &mut filters_by_source[source_index], &options);
+                    overwrite_last_n_lines(lines, pos_by_source[source_index], None, &matches_by_source[source_index], &options);
+                    write_status_message(&message, &options);
+                }
             }
         }
     }
 
-    execute!(stdout(), EnableLineWrap).unwrap();
-    execute!(stdout(), LeaveAlternateScreen).unwrap();
-    disable_raw_mode().expect("Could not exit raw mode");
+    restore_terminal();
 
-    // This will bring all of our threads down with us
-    exit(0);
+    // The first line on screen for each source, 1-based and counting lines hidden by a filter, to be saved for next time
+    sources.iter().enumerate().map(|(i, source)| {
+        let lines = source.lines.lock().expect("Could not take lock in term_thread");
+        let (lines, options) = filtered_view(&lines, &mut filters_by_source[i], &options);
+        if lines.is_empty() {
+            1
+        } else {
+            original_line_no(screen_top(pos_by_source[i], lines.len(), &options).min(lines.len() - 1), &options) + 1
+        }
+    }).collect()
 }
 
+// The lines to show for a source, which is only those matching its filter if it has one
+fn filtered_view<'a>(lines: &'a Lines, filter: &'a mut Option<Filter>, options: &RenderOptions<'a>) -> (View<'a>, RenderOptions<'a>) {
+    if filter.is_none() && options.squeeze_blank {
+        *filter = Some(Filter { query: String::new(), case_sensitive: false, squeeze_blank: true, line_nos: Vec::new(), checked: 0 });
+    }
 
-fn print_line(line: &str, highlight: bool) {
-    let mut output = stdout();
-    if highlight {
-        queue!(
-                output,
-                SetBackgroundColor(Color::Cyan),
-                SetForegroundColor(Color::Black),
-                Print(line),
-                ResetColor
-            ).unwrap();
-    }else {
-        queue!(output, Print(line)).unwrap();
-    }
-}
-
-fn trim_trailing_newlines(s: &str) -> &str {
-    let mut end = s.len();
-    for (i, c) in s.char_indices().rev() {
-        if c == '\n' || c == '\r' {
-            end = i;
-        } else {
-            break;
+    match filter {
+        Some(filter) => {
+            update_filter(filter, lines);
+            (View { lines, line_nos: Some(&filter.line_nos) }, RenderOptions { line_nos: Some(&filter.line_nos), ..*options })
         }
+        None => (View { lines, line_nos: None }, *options),
     }
-    &s[0..end]
 }
 
-fn overwrite_last_n_lines(lines: &Vec<String>, pos: Option<usize>, highlight_line_no: Option<usize>) {
-    let (cols, rows) = crossterm::terminal::size().expect("Could not get terminal size");
-    let mut output = stdout();
+// Lines dropped by --max-lines move everything that points into the source up by as many lines, and anything
+// pointing at one of them goes. Returns whether the line at the top of the screen was one of them
+fn forget_dropped_lines(n: usize, filter: &mut Option<Filter>, pos: &mut Option<usize>, matches: &mut Matches, marks: &mut HashMap<char, usize>) -> bool {
+    // Positions, matches and marks are lines in the view, so with a filter they only move by the filtered lines
+    let n_view = match filter {
+        Some(filter) => {
+            let n_filtered = filter.line_nos.partition_point(|&line_no| line_no < n);
+            filter.line_nos.drain(..n_filtered);
+            filter.line_nos.iter_mut().for_each(|line_no| *line_no -= n);
+            filter.checked = filter.checked.saturating_sub(n);
+            n_filtered
+        }
+        None => n,
+    };
 
-    queue!(output, crossterm::terminal::Clear(crossterm::terminal::ClearType::All), MoveTo(0, 0)).unwrap();
+    let n_matches = matches.line_nos.partition_point(|&line_no| line_no < n_view);
+    matches.line_nos.drain(..n_matches);
+    matches.line_nos.iter_mut().for_each(|line_no| *line_no -= n_view);
+    marks.retain(|_, line_no| *line_no >= n_view);
+    marks.values_mut().for_each(|line_no| *line_no -= n_view);
 
-    let mut max_displayed_lines = rows;
-    let mut start = pos.unwrap_or(
-        if lines.len() < rows as usize {
-            0
-        } else {
-            lines.len() - rows as usize + 2
+    match pos {
+        Some(top) => {
+            let dropped = *top < n_view;
+            *top = top.saturating_sub(n_view);
+            dropped
         }
-    );
-
-    if lines.len() - start < rows as usize - 1 {
-        let old_start = start;
-        start = if lines.len() > rows as usize {
-            lines.len() - (rows as usize - 1)
-        } else {
-            0
-        };
+        None => false,
+    }
+}
 
-        let diff = (old_start as isize) - (start as isize);
-        max_displayed_lines = rows + diff as u16;
+// Filters any lines that have come in since last time
+fn update_filter(filter: &mut Filter, lines: &Lines) {
+    // The source shrank (e.g. a watched file was truncated) so start again
+    if lines.len() < filter.checked {
+        filter.line_nos.clear();
+        filter.checked = 0;
     }
 
-    let mut displayed_lines = 0;
-    for i in start..(start + rows as usize - 1) {
-        if i >= lines.len() {
-            break;
+    let lines = View { lines, line_nos: None };
+    let matched = match search_pattern(&filter.query, false, filter.case_sensitive, false) {
+        Some(re) => search_lines(&re, lines, filter.checked),
+        None => Vec::new(),
+    };
+    for line_no in matched {
+        if filter.squeeze_blank && is_blank(&lines.line(line_no)) && filter.line_nos.last().is_some_and(|&last| is_blank(&lines.line(last))) {
+            continue;
         }
-        let mut cur_line = lines[i].as_str();
+        filter.line_nos.push(line_no);
+    }
+    filter.checked = lines.len();
+}
 
-        while pos.is_none() || displayed_lines < max_displayed_lines as usize - 1 {
-            if cur_line.len() > cols as usize {
-                print_line(format!("{}\r\n", trim_trailing_newlines(&cur_line[0..cols as usize])).as_str(), highlight_line_no == Some(i));
-                cur_line = &cur_line[cols as usize..];
-                displayed_lines += 1;
-            } else {
-                print_line(format!("{}\r\n", trim_trailing_newlines(cur_line)).as_str(), highlight_line_no == Some(i));
-                displayed_lines += 1;
-                break;
+// Whether the sources' lines all fit above the status bar together, counting any that would wrap
+fn fits_on_one_screen(sources: &[Arc<Source>], options: &RenderOptions) -> bool {
+    let (cols, rows) = options.size;
+
+    let mut n_rows = 0;
+    for source in sources.iter().filter(|source| source.reader.is_some()) {
+        let lines = source.lines.lock().expect("Could not take lock in fits_on_one_screen");
+        let gutter_width = if options.line_numbers { digit_count(lines.len()) + 1 } else { 0 };
+        let text_cols = (cols as usize).saturating_sub(gutter_width).max(1);
+        for line in lines.iter() {
+            let notated = notate_control_chars(&line, options);
+            let expanded = ansi::expand_tabs(&notated, options.tab_width);
+            n_rows += wrapped_row_count(&expanded, text_cols, options);
+            if n_rows > rows as usize - 1 {
+                return false;
             }
         }
     }
+    true
+}
 
-    output.flush().expect("Could not flush output");
+// How many screen rows a line takes up once it's been prepared for display like render_screen does
+fn line_rows(line: &str, text_cols: usize, options: &RenderOptions) -> usize {
+    let (expanded, _) = display_line(line, Highlight::Plain, &Matches::default(), options);
+    wrapped_row_count(&expanded, text_cols, options)
 }
 
-fn get_matches(lines: &Vec<String>, search: &str, is_regex: bool) -> Vec<usize> {
-    let search_as_lower = search.to_lowercase();
-    let mut matches = Vec::<usize>::new();
+// How many screen rows a line takes up, using the same splitting as overwrite_last_n_lines
+fn wrapped_row_count(line: &str, text_cols: usize, options: &RenderOptions) -> usize {
+    if options.chop_long_lines {
+        return 1;
+    }
+
+    let mut n_rows = 1;
+    let mut cur_line = line;
+    while ansi::visible_width(cur_line) > text_cols {
+        cur_line = if options.word_wrap {
+            ansi::split_at_word(cur_line, text_cols).1
+        } else {
+            ansi::split_at_width(cur_line, text_cols).1
+        };
+        n_rows += 1;
+    }
+    n_rows
+}
 
+// For -F, the lines go to the normal screen as if we were cat
+fn print_without_paging(lines: &Lines, options: &RenderOptions) {
+    let mut output = stdout();
+    let gutter_width = digit_count(lines.len()) + 1;
     for (i, line) in lines.iter().enumerate() {
-        let as_lower = line.to_lowercase();
-
-        if is_regex {
-            if let Ok(re) = regex::Regex::new(search) {
-                if re.is_match(line) {
-                    matches.push(i);
-                }
-            }
+        if options.line_numbers {
+            queue!(output, Print(gutter(i, gutter_width, false))).unwrap();
+        }
+        let filtered = ansi::filter_escapes(&line, options.color);
+        let filtered = notate_control_chars(&filtered, options);
+        if filtered.contains('\x1b') {
+            queue!(output, Print(filtered), ResetColor, Print("\n")).unwrap();
         } else {
-            if as_lower.contains(&search_as_lower) {
-                matches.push(i);
-            }
+            queue!(output, Print(filtered), Print("\n")).unwrap();
         }
     }
-
-    matches
+    output.flush().expect("Could not flush output");
 }
 
+fn is_blank(line: &str) -> bool {
+    line.is_empty()
+}
 
-fn write_status_message(message: &str) {
-    let (_, rows) = crossterm::terminal::size().expect("Could not get terminal size");
+// Where { } [[ and ]] look from: the line the last of them went to if it's still on screen, since that isn't always at
+// the top (e.g. with --scrolloff), otherwise the top of the screen
+fn motion_start(motion_line_no: Option<(usize, usize)>, source_index: usize, pos: Option<usize>, n_lines: usize, options: &RenderOptions) -> usize {
+    let (_, rows) = options.size;
+    let top = screen_top(pos, n_lines, options);
+    match motion_line_no {
+        Some((i, line_no)) if i == source_index && (top..top + rows as usize - 1).contains(&line_no) => line_no,
+        _ => top,
+    }
+}
 
+// The next blank line after line_no (or before it, going backward), for { and }. Like vim's paragraph motion, a run
+// of blank lines only counts once, at the first of them
+fn next_blank_line(lines: View, line_no: usize, backward: bool) -> Option<usize> {
+    let blank = |i: usize| lines.line(i).trim().is_empty();
+    let starts_run = |&i: &usize| blank(i) && (i == 0 || !blank(i - 1));
+    if backward {
+        (0..line_no.min(lines.len())).rev().find(starts_run)
+    } else {
+        (line_no + 1..lines.len()).find(starts_run)
+    }
+}
+
+// The next line matching re after line_no, or before it going backward
+fn next_matching_line(lines: View, re: &regex::Regex, line_no: usize, backward: bool) -> Option<usize> {
+    if backward {
+        (0..line_no.min(lines.len())).rev().find(|&i| re.is_match(&lines.line(i)))
+    } else {
+        (line_no + 1..lines.len()).find(|&i| re.is_match(&lines.line(i)))
+    }
+}
+
+// Line numbers the user sees (the gutter, go to line, search results) are always from the source, not the view
+fn original_line_no(line_no: usize, options: &RenderOptions) -> usize {
+    options.line_nos.map_or(line_no, |line_nos| line_nos[line_no]) + options.dropped_lines
+}
+
+// The line in the view showing the given source line, or the next one after it if it's been filtered out
+fn view_line_no(line_no: usize, options: &RenderOptions) -> usize {
+    let line_no = line_no.saturating_sub(options.dropped_lines);
+    options.line_nos.map_or(line_no, |line_nos| line_nos.partition_point(|&n| n < line_no))
+}
+
+fn original_line_count(lines: View, options: &RenderOptions) -> usize {
+    options.line_nos.map_or(lines.len(), |line_nos| line_nos.last().map_or(0, |n| n + 1)) + options.dropped_lines
+}
+
+// For --dump, prints what the first screen would look like (status bar included) instead of paging
+pub fn dump_screen(source: &Source, options: RenderOptions, search_options: SearchOptions, start: Option<StartPosition>) {
+    let lines = source.lines.lock().expect("Could not take lock in dump_screen");
+    let mut filter = None;
+    let (lines, options) = filtered_view(&lines, &mut filter, &options);
+
+    let mut pos = Some(0);
+    let mut matches = Matches::default();
+    match start {
+        Some(StartPosition::Line(line_no)) => {
+            pos = clamp_pos(Some(view_line_no(line_no.saturating_sub(1), &options)), lines.len());
+        }
+        Some(StartPosition::Pattern(query)) => {
+            matches = get_matches(lines, &query, false, is_case_sensitive(&query, &search_options), search_options.whole_word);
+            if let Some(&line_no) = matches.first() {
+                pos = pos_with_in_view(Some(line_no), jump_offset(&options));
+            }
+        }
+        None => {}
+    }
+
+    let screen = render_screen(lines, pos, None, &matches, &options);
+    let mut output = stdout();
+    for row in screen.rows {
+        queue!(output, Print(row), Print("\n")).unwrap();
+    }
+    queue!(output, Print(position_status(screen.start, screen.last_displayed_line, lines.len(), pos)), Print("\n")).unwrap();
+    output.flush().expect("Could not flush output");
+}
+
+// Hands the terminal over to something else (e.g. an editor) until resume_terminal is called
+// The input thread is paused as well, otherwise it'd steal keypresses
+fn suspend_terminal(input_tx: &mpsc::Sender<InputThreadMessage>) {
+    let (ack_tx, ack_rx) = mpsc::channel::<()>();
+    if input_tx.send(InputThreadMessage::Pause(ack_tx)).is_ok() {
+        let _ = ack_rx.recv();
+    }
+    restore_terminal();
+}
+
+// We won't have seen any resizes while something else had the terminal, so the caller should ask for the size again
+fn resume_terminal(input_tx: &mpsc::Sender<InputThreadMessage>) {
+    enable_raw_mode().expect("Could not enter raw mode");
+    execute!(stdout(), EnterAlternateScreen, DisableLineWrap).unwrap();
+    set_mouse_capture(MOUSE_CAPTURE.load(Ordering::Relaxed));
+    let _ = input_tx.send(InputThreadMessage::Resume);
+}
+
+// Opens $EDITOR (or vi) at the given 1-based line, waiting for it to exit
+fn open_in_editor(path: &std::path::Path, line_no: usize, input_tx: &mpsc::Sender<InputThreadMessage>) -> std::io::Result<()> {
+    let editor = std::env::var("EDITOR").ok().filter(|e| !e.trim().is_empty()).unwrap_or("vi".to_string());
+    // $EDITOR can have arguments in it, e.g. "code --wait"
+    let mut words = editor.split_whitespace();
+    let program = words.next().unwrap_or("vi");
+
+    suspend_terminal(input_tx);
+    let result = std::process::Command::new(program)
+        .args(words)
+        .arg(format!("+{}", line_no))
+        .arg(path)
+        .status();
+    resume_terminal(input_tx);
+
+    result.map(|_| ())
+}
+
+// Like less's |, runs a shell command with the lines on its stdin and the terminal to itself, so it can be interactive
+// Waits for Enter once it's done, otherwise we'd draw over its output before it could be read
+fn pipe_to_command(command: &str, lines: impl Iterator<Item = impl std::fmt::Display>, input_tx: &mpsc::Sender<InputThreadMessage>) -> std::io::Result<std::process::ExitStatus> {
+    let (shell, flag) = if cfg!(windows) { ("cmd", "/C") } else { ("sh", "-c") };
+
+    suspend_terminal(input_tx);
+    let result = std::process::Command::new(shell)
+        .arg(flag)
+        .arg(command)
+        .stdin(std::process::Stdio::piped())
+        .spawn()
+        .and_then(|mut child| {
+            if let Some(stdin) = child.stdin.take() {
+                let mut stdin = std::io::BufWriter::new(stdin);
+                // A command that doesn't read all of its input (e.g. head) closes the pipe early, which is fine
+                for line in lines {
+                    if writeln!(stdin, "{}", line).is_err() {
+                        break;
+                    }
+                }
+                let _ = stdin.flush();
+            }
+            child.wait()
+        });
+    if result.is_ok() {
+        print!("Press Enter to continue");
+        let _ = stdout().flush();
+        wait_for_enter();
+    }
+    resume_terminal(input_tx);
+
+    result
+}
+
+// Reads from the terminal like the input thread (which is paused meanwhile) rather than stdin, since stdin can be the
+// content being paged
+fn wait_for_enter() {
+    if enable_raw_mode().is_err() {
+        return;
+    }
+    loop {
+        match crossterm::event::read() {
+            Ok(crossterm::event::Event::Key(event)) if event.kind == KeyEventKind::Press && event.code == crossterm::event::KeyCode::Enter => break,
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+    let _ = disable_raw_mode();
+}
+
+// Asks the terminal how big it is, see RenderOptions::size
+fn terminal_size() -> (u16, u16) {
+    let (cols, rows) = crossterm::terminal::size().expect("Could not get terminal size");
+    usable_size(cols, rows)
+}
+
+// Some terminals briefly report 0x0 mid-resize, everything assumes there's at least a row for text and one for the
+// status bar
+fn usable_size(cols: u16, rows: u16) -> (u16, u16) {
+    (cols.max(1), rows.max(2))
+}
+
+// Errors are ignored since this also runs while panicking, when there's nothing better to do anyway
+fn restore_terminal() {
+    let _ = disable_raw_mode();
+    let _ = execute!(stdout(), LeaveAlternateScreen, EnableLineWrap, DisableMouseCapture);
+}
+
+fn set_mouse_capture(mouse: bool) {
+    MOUSE_CAPTURE.store(mouse, Ordering::Relaxed);
+    if mouse {
+        execute!(stdout(), EnableMouseCapture).unwrap();
+    } else {
+        execute!(stdout(), DisableMouseCapture).unwrap();
+    }
+}
+
+// Without this a panic leaves the terminal in raw mode on the alternate screen, and the panic message gets lost
+fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        restore_terminal();
+        default_hook(info);
+    }));
+}
+
+
+#[derive(Clone, Copy, PartialEq)]
+enum Highlight {
+    Plain,
+    // Any line matching the last search
+    Match,
+    // The match we jumped to, or the line we went to
+    Current,
+}
+
+fn highlight_for(line_no: usize, highlight_line_no: Option<usize>, match_lines: &Matches) -> Highlight {
+    if highlight_line_no == Some(line_no) {
+        Highlight::Current
+    } else if match_lines.line_nos.binary_search(&line_no).is_ok() {
+        Highlight::Match
+    } else {
+        Highlight::Plain
+    }
+}
+
+// The escape sequence that starts a highlight, it's ended with a reset
+fn highlight_sgr(highlight: Highlight, options: &RenderOptions) -> String {
+    let mut sgr = String::new();
+    if options.ui_color {
+        let theme = options.theme;
+        let (background, foreground) = if highlight == Highlight::Current { (theme.highlight_bg, theme.highlight_fg) } else { (theme.match_bg, theme.match_fg) };
+        SetBackgroundColor(background).write_ansi(&mut sgr).unwrap();
+        SetForegroundColor(foreground).write_ansi(&mut sgr).unwrap();
+    } else {
+        let attribute = if highlight == Highlight::Current { Attribute::Reverse } else { Attribute::Underlined };
+        SetAttribute(attribute).write_ansi(&mut sgr).unwrap();
+    }
+    sgr
+}
+
+// Styles one screen row, the line passed in shouldn't contain any newlines
+// Any colours from the input are reset at the end of the row so they don't bleed into the next one
+fn styled_row(line: &str, highlight: Highlight, options: &RenderOptions) -> String {
+    let mut row = String::new();
+    if highlight != Highlight::Plain {
+        row.push_str(&highlight_sgr(highlight, options));
+        // The input's own colours would fight with the highlight, so drop them
+        row.push_str(&ansi::filter_escapes(line, false));
+        SetAttribute(Attribute::Reset).write_ansi(&mut row).unwrap();
+    } else {
+        row.push_str(line);
+        if line.contains('\x1b') {
+            SetAttribute(Attribute::Reset).write_ansi(&mut row).unwrap();
+        }
+    }
+    row
+}
+
+// A line as it's drawn (before wrapping), and how the whole of it should be highlighted
+// On a line matching the search just the matched parts are highlighted, marked with escapes here so they survive
+// being wrapped. The input's own colours are dropped on those lines like styled_row does. If the match can't be
+// found in what's drawn (e.g. it spans a tab) or we went to the line rather than searching, the whole line is
+// highlighted instead
+fn display_line(line: &str, highlight: Highlight, match_lines: &Matches, options: &RenderOptions) -> (String, Highlight) {
+    let filtered = ansi::filter_escapes(line, options.color && highlight == Highlight::Plain);
+    let notated = notate_control_chars(&filtered, options);
+    let expanded = if options.show_whitespace {
+        ansi::show_whitespace(&notated, options.tab_width)
+    } else {
+        ansi::expand_tabs(&notated, options.tab_width).into_owned()
+    };
+
+    let ranges = match (&match_lines.pattern, highlight) {
+        (Some(re), Highlight::Match | Highlight::Current) => match_ranges(re, &expanded),
+        _ => Vec::new(),
+    };
+    if ranges.is_empty() {
+        return (expanded, highlight);
+    }
+
+    let sgr = highlight_sgr(highlight, options);
+    let mut marked = String::with_capacity(expanded.len() + ranges.len() * (sgr.len() + 4));
+    let mut end = 0;
+    for range in ranges {
+        marked.push_str(&expanded[end..range.start]);
+        marked.push_str(&sgr);
+        marked.push_str(&expanded[range.clone()]);
+        SetAttribute(Attribute::Reset).write_ansi(&mut marked).unwrap();
+        end = range.end;
+    }
+    marked.push_str(&expanded[end..]);
+    (marked, Highlight::Plain)
+}
+
+// Empty matches (e.g. from a regex like a*) have nothing to highlight
+fn match_ranges(re: &regex::Regex, line: &str) -> Vec<Range<usize>> {
+    re.find_iter(line).map(|m| m.range()).filter(|range| !range.is_empty()).collect()
+}
+
+// Wrapped rows leave the gutter blank so the numbers stay aligned with the start of each line
+fn gutter(line_no: usize, gutter_width: usize, is_continuation: bool) -> String {
+    if is_continuation {
+        " ".repeat(gutter_width)
+    } else {
+        format!("{:>width$} ", line_no + 1, width = gutter_width - 1)
+    }
+}
+
+fn digit_count(n: usize) -> usize {
+    n.max(1).ilog10() as usize + 1
+}
+
+// Overstruck chars are styled first, since their backspaces would otherwise be shown as ^H
+fn notate_control_chars<'a>(line: &'a str, options: &RenderOptions) -> Cow<'a, str> {
+    let line = if options.overstrike { ansi::overstrike(line) } else { Cow::Borrowed(line) };
+    if options.raw_control_chars {
+        line
+    } else {
+        match line {
+            Cow::Borrowed(line) => ansi::caret_notation(line),
+            Cow::Owned(line) => Cow::Owned(ansi::caret_notation(&line).into_owned()),
+        }
+    }
+}
+
+// What render_screen would put on screen, and which lines that covers for the status bar
+struct Screen {
+    rows: Vec<String>,
+    start: usize,
+    last_displayed_line: Option<usize>,
+}
+
+// Where the text goes: how many --header lines are pinned to the top (a row each), and the columns and rows left for
+// the rest above the status bar. A long line can wrap onto several rows, so the rows aren't a count of lines
+fn text_area(lines: View, cols: usize, rows: usize, options: &RenderOptions) -> (usize, usize, usize) {
+    // Same as terminal_size, there's always room for at least one row of text
+    let cols = cols.max(1);
+    let rows = rows.max(2);
+    let header = options.header.min(lines.len()).min(rows - 2);
+    let gutter_width = if options.line_numbers { digit_count(original_line_count(lines, options)) + 1 } else { 0 };
+    let text_cols = cols.saturating_sub(gutter_width).max(1);
+    (header, text_cols, rows - header - 1)
+}
+
+// The first line on screen and how many of its rows are scrolled off the top, see RenderOptions::top_segment
+// In autoscroll it's wherever fills the screen with the end of the input. A position can also be too near the end to
+// fill the screen (e.g. a watched file was truncated under it), then it's pulled back to the same place
+fn screen_start(lines: View, pos: Option<usize>, header: usize, text_cols: usize, text_rows: usize, options: &RenderOptions) -> (usize, usize) {
+    let tail = {
+        let mut start = lines.len();
+        let mut n_rows = 0;
+        let mut skip = 0;
+        while start > header && n_rows < text_rows {
+            let line_rows = line_rows(&lines.line(start - 1), text_cols, options);
+            start -= 1;
+            // Only the end of a line that doesn't fit is on screen
+            skip = line_rows.saturating_sub(text_rows - n_rows);
+            n_rows += line_rows - skip;
+        }
+        (start, skip)
+    };
+
+    match pos {
+        None => tail,
+        Some(pos) => {
+            let pos = pos.max(header);
+            let (line_no, skip) = options.top_segment;
+            let skip = if line_no == pos && !options.chop_long_lines { skip } else { 0 };
+            (pos, skip).min(tail)
+        }
+    }
+}
+
+// Works out the rows to draw without touching the terminal, overwrite_last_n_lines does the drawing
+// Everything it depends on (the screen size and colours included) is passed in, so the same input always draws the same
+fn render_screen(lines: View, pos: Option<usize>, highlight_line_no: Option<usize>, match_lines: &Matches, options: &RenderOptions) -> Screen {
+    let (cols, rows) = options.size;
+    // Same as usable_size, there's always room for at least one row of text
+    let cols = (cols as usize).max(1);
+    let rows = (rows as usize).max(2);
+
+    let (header, text_cols, text_rows) = text_area(lines, cols, rows, options);
+    let gutter_width = if options.line_numbers { digit_count(original_line_count(lines, options)) + 1 } else { 0 };
+    let (start, skip) = screen_start(lines, pos, header, text_cols, text_rows, options);
+
+    let mut header_rows = Vec::new();
+    for i in 0..header {
+        let (expanded, highlight) = display_line(&lines.line(i), highlight_for(i, highlight_line_no, match_lines), match_lines, options);
+        let (text, _) = ansi::split_at_width(&expanded, text_cols);
+        let mut row = if options.line_numbers { gutter(original_line_no(i, options), gutter_width, false) } else { String::new() };
+        row.push_str(&styled_row(text, highlight, options));
+        header_rows.push(row);
+    }
+
+    let mut screen_rows = Vec::new();
+    let mut last_displayed_line = None;
+    // The rows of the first line that have been scrolled off the top
+    let mut skip = skip;
+    for i in start..lines.len() {
+        if screen_rows.len() >= text_rows {
+            break;
+        }
+        let (expanded, highlight) = display_line(&lines.line(i), highlight_for(i, highlight_line_no, match_lines), match_lines, options);
+        let mut cur_line: &str = &expanded;
+        let mut active_sgr = String::new();
+        let mut is_continuation = false;
+
+        let mut line_rows = Vec::new();
+        while line_rows.len() < text_rows - screen_rows.len() + skip {
+            let mut row = if options.line_numbers {
+                gutter(original_line_no(i, options), gutter_width, is_continuation)
+            } else {
+                String::new()
+            };
+
+            if options.chop_long_lines && ansi::visible_width(cur_line) > text_cols {
+                // Leave room for a marker so it's clear the line goes on
+                let (text, _) = ansi::split_at_width(cur_line, text_cols.saturating_sub(1));
+                let reset = if text.contains('\x1b') { "\x1b[0m" } else { "" };
+                row.push_str(&styled_row(format!("{}{}>", text, reset).as_str(), highlight, options));
+                line_rows.push(row);
+                break;
+            } else if ansi::visible_width(cur_line) > text_cols {
+                let (text, rest) = if options.word_wrap {
+                    ansi::split_at_word(cur_line, text_cols)
+                } else {
+                    ansi::split_at_width(cur_line, text_cols)
+                };
+                row.push_str(&styled_row(format!("{}{}", active_sgr, text).as_str(), highlight, options));
+                line_rows.push(row);
+                active_sgr = ansi::active_sgr(format!("{}{}", active_sgr, text).as_str());
+                cur_line = rest;
+                is_continuation = true;
+            } else {
+                row.push_str(&styled_row(format!("{}{}", active_sgr, cur_line).as_str(), highlight, options));
+                line_rows.push(row);
+                break;
+            }
+        }
+
+        if line_rows.len() > skip {
+            last_displayed_line = Some(i);
+        }
+        screen_rows.extend(line_rows.into_iter().skip(skip));
+        skip = 0;
+    }
+
+    header_rows.append(&mut screen_rows);
+    Screen { rows: header_rows, start, last_displayed_line }
+}
+
+fn overwrite_last_n_lines(lines: View, pos: Option<usize>, highlight_line_no: Option<usize>, match_lines: &Matches, options: &RenderOptions) {
+    let screen = render_screen(lines, pos, highlight_line_no, match_lines, options);
+    let mut output = stdout();
+
+    queue!(output, crossterm::terminal::Clear(crossterm::terminal::ClearType::All), MoveTo(0, 0)).unwrap();
+    // Raw mode turns off the terminal's own translation of \n to \r\n
+    for row in screen.rows {
+        queue!(output, Print(row), Print("\r\n")).unwrap();
+    }
+    output.flush().expect("Could not flush output");
+
+    write_status_message(&position_status(screen.start, screen.last_displayed_line, lines.len(), pos), options);
+}
+
+fn position_status(start: usize, last_displayed_line: Option<usize>, n_lines: usize, pos: Option<usize>) -> String {
+    let range = match last_displayed_line {
+        Some(last) => format!("lines {}-{} / {}", start + 1, last + 1, n_lines),
+        None => format!("lines - / {}", n_lines),
+    };
+
+    // Like less, anywhere the last line is on screen counts as the end, not just autoscroll
+    if pos.is_none() || last_displayed_line.is_some_and(|last| last + 1 >= n_lines) {
+        format!("{} (END)", range)
+    } else {
+        let percent = last_displayed_line.map(|last| (last + 1) * 100 / n_lines).unwrap_or(0);
+        format!("{} ({}%)", range, percent)
+    }
+}
+
+// Where the source is in the rotation and how big it is, n_lines being all of its lines rather than a filter's
+fn switched_status(sources: &[Arc<Source>], source_index: usize, n_lines: usize) -> String {
+    format!("Switched to source {}/{}: {} ({} lines)", source_index + 1, sources.len(), sources[source_index].name, n_lines)
+}
+
+// Shown with `=` or Ctrl-G, like less
+fn file_info(source: &Source, lines: View, pos: Option<usize>, options: &RenderOptions) -> String {
+    let screen = render_screen(lines, pos, None, &Matches::default(), options);
+    let position = position_status(screen.start, screen.last_displayed_line, lines.len(), pos);
+
+    let size = source.path.as_ref().and_then(|path| std::fs::metadata(path).ok()).map(|metadata| metadata.len());
+    match size {
+        Some(size) => format!("\"{}\" {}, {} bytes", source.name, position, size),
+        None => format!("{} {}, {} bytes read so far", source.name, position, source.bytes_read.load(Ordering::Relaxed)),
+    }
+}
+
+fn get_matches(lines: View, search: &str, is_regex: bool, case_sensitive: bool, whole_word: bool) -> Matches {
+    let pattern = search_pattern(search, is_regex, case_sensitive, whole_word);
+    let line_nos = match &pattern {
+        Some(re) => search_lines(re, lines, 0),
+        None => Vec::new(),
+    };
+    Matches { line_nos, pattern }
+}
+
+// The lines from first_line_no on that match
+fn search_lines(re: &regex::Regex, lines: View, first_line_no: usize) -> Vec<usize> {
+    let is_match = |i: &usize| re.is_match(&lines.line(*i));
+    // Splitting the work up costs more than it saves on small inputs, and indexed lines are read back from the
+    // file one at a time anyway
+    if lines.len().saturating_sub(first_line_no) < PARALLEL_SEARCH_LINES || matches!(lines.lines, Lines::Indexed(_)) {
+        return (first_line_no..lines.len()).filter(is_match).collect();
+    }
+    // Chunks are collected in order, so the line numbers come out sorted
+    (first_line_no..lines.len()).into_par_iter().with_min_len(SEARCH_CHUNK_LINES).filter(is_match).collect()
+}
+
+// Plain searches are escaped so they can be matched the same way as regexes
+// Callers are expected to have checked a regex with regex_error already, one that doesn't compile matches nothing
+fn search_pattern(search: &str, is_regex: bool, case_sensitive: bool, whole_word: bool) -> Option<regex::Regex> {
+    if is_regex {
+        regex::Regex::new(search).ok()
+    } else {
+        let escaped = regex::escape(search);
+        // So that e.g. `in` doesn't match inside `print`
+        let pattern = if whole_word { format!(r"\b{}\b", escaped) } else { escaped };
+        regex::RegexBuilder::new(&pattern).case_insensitive(!case_sensitive).build().ok()
+    }
+}
+
+// The regex crate's errors span several lines pointing at the problem, we only have room for the last bit
+fn regex_error(search: &str) -> Option<String> {
+    match regex::Regex::new(search) {
+        Ok(_) => None,
+        Err(regex::Error::Syntax(message)) => {
+            let summary = message.lines()
+                .find_map(|line| line.strip_prefix("error: "))
+                .unwrap_or(message.as_str());
+            Some(summary.to_string())
+        }
+        Err(e) => Some(e.to_string()),
+    }
+}
+
+fn write_status_message(message: &str, options: &RenderOptions) {
+    let (_, rows) = options.size;
+
+    let mut output = stdout();
+    if options.ui_color {
+        queue!(output, SetBackgroundColor(options.theme.status_bg), SetForegroundColor(options.theme.status_fg)).unwrap();
+    } else {
+        queue!(output, SetAttribute(Attribute::Reverse)).unwrap();
+    }
     execute!(
-        stdout(),
-        SetBackgroundColor(Color::Grey),
-        SetForegroundColor(Color::Black),
+        output,
         MoveTo(0, rows - 1),
         Clear(ClearType::CurrentLine),
         Print(message),
-        ResetColor
+        SetAttribute(Attribute::Reset)
     ).unwrap();
 }
 
-fn jump_to_match(lines: &Vec<String>, matches: &Vec<usize>, pos: &mut Option<usize>, page_up_size: usize, match_no: usize) -> Result<(), ()> {
-    let mut highlight_line_no = None;
+fn match_status(matches: &[usize], match_no: usize, options: &RenderOptions) -> String {
+    format!("Match {}/{} on line {}", match_no + 1, matches.len(), original_line_no(matches[match_no], options) + 1)
+}
+
+// less's wording for when a search runs off one end and carries on from the other
+fn wrapped_status(matches: &[usize], match_no: usize, backward: bool, options: &RenderOptions) -> String {
+    let wrap = if backward { "search hit top, continuing at bottom" } else { "search hit bottom, continuing at top" };
+    format!("{} ({})", wrap, match_status(matches, match_no, options))
+}
+
+// The match after (or before) match_no, and whether getting there wrapped around
+fn step_match(match_no: usize, n_matches: usize, backward: bool) -> (usize, bool) {
+    if backward {
+        if match_no > 0 { (match_no - 1, false) } else { (n_matches - 1, true) }
+    } else if match_no + 1 < n_matches {
+        (match_no + 1, false)
+    } else {
+        (0, true)
+    }
+}
 
+fn jump_to_match(lines: View, matches: &Matches, pos: &mut Option<usize>, page_up_size: usize, match_no: usize, options: &RenderOptions) -> Result<(), ()> {
     if match_no < matches.len() {
         *pos = pos_with_in_view(Some(matches[match_no]), page_up_size);
-        highlight_line_no = Some(matches[match_no]);
-        overwrite_last_n_lines(&lines, *pos, highlight_line_no);
+        let highlight_line_no = Some(matches[match_no]);
+        overwrite_last_n_lines(lines, *pos, highlight_line_no, matches, options);
 
-        write_status_message(&format!("Match {}/{} on line {}", match_no + 1, matches.len(), matches[match_no] + 1));
+        write_status_message(&match_status(matches, match_no, options), options);
         Ok(())
     } else {
         Err(())
     }
 }
 
-// Note, search mode ignores many of the events from term_rx. It has special permission to do so.
-fn handle_search_mode(pos: &mut Option<usize>, lines_mtx: &Mutex<Vec<String>>, term_rx: &mpsc::Receiver<TerminalThreadMessage>, page_up_size: usize, match_regex: bool) {
-    // Is it right to hold the lock for this whole time? Or would the user want to see new results as they come in?
-    let lines= lines_mtx.lock().expect("Could not take lock in search event handler");
+fn is_case_sensitive(search: &str, search_options: &SearchOptions) -> bool {
+    if search_options.smartcase {
+        search.chars().any(char::is_uppercase)
+    } else {
+        search_options.case_sensitive
+    }
+}
 
+fn search_prompt(kind: SearchKind, search: &str, search_options: &SearchOptions) -> String {
+    let mut prompt = String::from(if kind.regex { "Regex" } else { "Search" });
+    if kind.backward {
+        prompt.push_str(" backward");
+    }
+    if !kind.regex && is_case_sensitive(search, search_options) {
+        prompt.push_str(" (case)");
+    }
+    if !kind.regex && search_options.whole_word {
+        prompt.push_str(" (word)");
+    }
+    prompt
+}
+
+// Searches start from where we were, i.e. the first match on or after the top of the screen, or the nearest
+// match above it for backward searches. If there's nothing in that direction we wrap around, which is
+// reported in the second element
+fn initial_match_no(matches: &[usize], start_pos: Option<usize>, n_lines: usize, kind: SearchKind) -> (usize, bool) {
+    if kind.backward {
+        let top = start_pos.unwrap_or(n_lines);
+        match matches.iter().rposition(|&line_no| line_no < top) {
+            Some(match_no) => (match_no, false),
+            None => (matches.len().saturating_sub(1), true),
+        }
+    } else {
+        let top = start_pos.unwrap_or(0);
+        match matches.iter().position(|&line_no| line_no >= top) {
+            Some(match_no) => (match_no, false),
+            None => (0, true),
+        }
+    }
+}
+
+// Note, search mode ignores many of the events from term_rx. It has special permission to do so.
+fn handle_search_mode(pos: &mut Option<usize>, lines: View, term_rx: &mpsc::Receiver<TerminalThreadMessage>, kind: SearchKind, search_options: &mut SearchOptions, match_lines: &mut Matches, options: &RenderOptions) -> Option<LastSearch> {
+    // lines is a snapshot (see search_snapshot), anything read while the query's being typed waits for the next search
     let mut highlight_line_no = None;
     let mut search = String::new();
+    let mut cursor = 0;
+    // Where we are in the history, one past the end is what's being typed, which is kept in draft while we're away
+    let mut history_index = search_options.history.len();
+    let mut draft = String::new();
+    let start_pos = *pos;
 
-    let prompt = if match_regex { "Regex" } else { "Search" };
-    write_status_message(&format!("{}: ", prompt));
+    write_input_status(&search_prompt(kind, &search, search_options), &search, cursor, "", options);
     loop {
-        match term_rx.recv() {
-            Ok(TerminalThreadMessage::KeyEvent(event)) => {
-                if event.kind != KeyEventKind::Press {
-                    continue;
-                }
-                match event.code {
-                    crossterm::event::KeyCode::Char(c) => {
-                        search.push(c);
-                    }
-                    crossterm::event::KeyCode::Backspace => {
-                        if search.len() > 0 {
-                            search.pop();
-                        } else {
-                            overwrite_last_n_lines(&lines, *pos, highlight_line_no);
-                            return;
-                        }
-                    }
-                    crossterm::event::KeyCode::Esc => {
-                        overwrite_last_n_lines(&lines, *pos, highlight_line_no);
-                        return;
-                    }
-                    crossterm::event::KeyCode::Enter => {
-                        break;
-                    }
-                    _ => {
+        match read_line_input(&mut search, &mut cursor, |_| true, term_rx) {
+            InputKey::Edited => {}
+            InputKey::Other(event) if event.code == crossterm::event::KeyCode::Char('s') && event.modifiers.contains(KeyModifiers::CONTROL) => {
+                search_options.case_sensitive = !is_case_sensitive(&search, search_options);
+                search_options.smartcase = false;
+            }
+            InputKey::Other(event) if event.code == crossterm::event::KeyCode::Char('b') && event.modifiers.contains(KeyModifiers::CONTROL) => {
+                search_options.whole_word = !search_options.whole_word;
+            }
+            // Up goes back through earlier searches, Down comes forward again
+            InputKey::Other(event) if matches!(event.code, crossterm::event::KeyCode::Up | crossterm::event::KeyCode::Down) => {
+                let history = &search_options.history;
+                if event.code == crossterm::event::KeyCode::Up && history_index > 0 {
+                    if history_index == history.len() {
+                        draft = search.clone();
                     }
+                    history_index -= 1;
+                } else if event.code == crossterm::event::KeyCode::Down && history_index < history.len() {
+                    history_index += 1;
+                } else {
+                    continue;
                 }
-            },
-            _ => {
+                search = history.get(history_index).cloned().unwrap_or_else(|| draft.clone());
+                cursor = search.len();
+            }
+            InputKey::Other(_) => {
+                continue;
+            }
+            InputKey::Cancel => {
+                overwrite_last_n_lines(lines, *pos, highlight_line_no, match_lines, options);
+                return None;
+            }
+            // An invalid regex keeps the prompt open so it can be fixed
+            InputKey::Submit if !kind.regex || regex_error(search.trim()).is_none() => {
+                break;
+            }
+            InputKey::Submit => {}
+        }
+
+        if kind.regex {
+            if let Some(error) = regex_error(search.trim()) {
+                write_input_status(&search_prompt(kind, &search, search_options), &search, cursor, &format!("  (Invalid regex: {})", error), options);
                 continue;
             }
         }
-        let matches = get_matches(&lines, search.trim(), match_regex);
-        let _ = jump_to_match(&lines, &matches, pos, page_up_size, 0);
-        write_status_message(&format!("{}: {}", prompt, search));
+
+        let matches = get_matches(lines, search.trim(), kind.regex, is_case_sensitive(search.trim(), search_options), search_options.whole_word);
+        let _ = jump_to_match(lines, &matches, pos, jump_offset(options), initial_match_no(&matches, start_pos, lines.len(), kind).0, options);
+        write_input_status(&search_prompt(kind, &search, search_options), &search, cursor, "", options);
+    }
+
+    // Searching for the same thing a few times in a row only needs one entry
+    if !search.trim().is_empty() && search_options.history.last().map(String::as_str) != Some(search.trim()) {
+        search_options.history.push(search.trim().to_string());
     }
 
     {
-        let mut match_no = 0;
-        let matches = get_matches(&lines, search.trim(), match_regex);
-        let _ = jump_to_match(&lines, &matches, pos, page_up_size, match_no);
+        *match_lines = get_matches(lines, search.trim(), kind.regex, is_case_sensitive(search.trim(), search_options), search_options.whole_word);
+        let matches = &*match_lines;
+        if matches.is_empty() {
+            overwrite_last_n_lines(lines, *pos, None, matches, options);
+            write_status_message("Pattern not found", options);
+            return Some(LastSearch { query: search.trim().to_string(), kind, line_no: None });
+        }
+        let (mut match_no, wrapped) = initial_match_no(matches, start_pos, lines.len(), kind);
+        let _ = jump_to_match(lines, matches, pos, jump_offset(options), match_no, options);
+        if wrapped {
+            write_status_message(&wrapped_status(matches, match_no, kind.backward, options), options);
+        }
 
         loop {
             match term_rx.recv() {
@@ -326,16 +1915,32 @@ fn handle_search_mode(pos: &mut Option<usize>, lines_mtx: &Mutex<Vec<String>>, t
                     match event.code {
                         crossterm::event::KeyCode::Esc | crossterm::event::KeyCode::Char('q') => {
                             highlight_line_no = None;
-                            overwrite_last_n_lines(&lines, *pos, highlight_line_no);
+                            overwrite_last_n_lines(lines, *pos, highlight_line_no, matches, options);
                             break;
                         }
-                        crossterm::event::KeyCode::Char('n') | crossterm::event::KeyCode::Down | crossterm::event::KeyCode::Right | crossterm::event::KeyCode::Enter => {
-                            match_no = (match_no + 1) % matches.len();
-                            let _ = jump_to_match(&lines, &matches, pos, page_up_size, match_no);
+                        // n and Enter go in the direction of the search, p the other way, arrow keys keep their direction
+                        crossterm::event::KeyCode::Char('n') | crossterm::event::KeyCode::Enter | crossterm::event::KeyCode::Char('p') | crossterm::event::KeyCode::Down | crossterm::event::KeyCode::Right | crossterm::event::KeyCode::Up | crossterm::event::KeyCode::Left => {
+                            let backward = match event.code {
+                                crossterm::event::KeyCode::Char('n') | crossterm::event::KeyCode::Enter => kind.backward,
+                                crossterm::event::KeyCode::Char('p') => !kind.backward,
+                                crossterm::event::KeyCode::Up | crossterm::event::KeyCode::Left => true,
+                                _ => false,
+                            };
+                            let (next_match_no, wrapped) = step_match(match_no, matches.len(), backward);
+                            match_no = next_match_no;
+                            let _ = jump_to_match(lines, matches, pos, jump_offset(options), match_no, options);
+                            if wrapped {
+                                write_status_message(&wrapped_status(matches, match_no, backward, options), options);
+                            }
                         }
-                        crossterm::event::KeyCode::Char('p') | crossterm::event::KeyCode::Up |  crossterm::event::KeyCode::Left => {
-                            match_no = if match_no > 0 { match_no - 1 } else { matches.len() - 1 };
-                            let _ = jump_to_match(&lines, &matches, pos, page_up_size, match_no);
+                        crossterm::event::KeyCode::Char('w') => {
+                            match read_prompt("Save matching lines to", term_rx, options) {
+                                Some(path) if !path.is_empty() => match save_matches(&path, lines, matches, options) {
+                                    Ok(()) => write_status_message(&format!("Saved {} lines to {}", matches.len(), path), options),
+                                    Err(e) => write_status_message(&format!("Could not save to {}: {}", path, e), options),
+                                },
+                                _ => write_status_message(&match_status(matches, match_no, options), options),
+                            }
                         }
                         _ => {
                         }
@@ -346,65 +1951,384 @@ fn handle_search_mode(pos: &mut Option<usize>, lines_mtx: &Mutex<Vec<String>>, t
                 }
             }
         }
+
+        Some(LastSearch { query: search.trim().to_string(), kind, line_no: Some(matches[match_no]) })
     }
 }
 
-fn handle_go_to_line(pos: Option<usize>, n_lines: usize, term_rx: &mpsc::Receiver<TerminalThreadMessage>) -> Option<usize> {
-    write_status_message("Go to line: ");
-    let mut line_no = String::new();
+// One matching line per line of the file, starting with its line number (and a tab) if line numbers are on
+fn save_matches(path: &str, lines: View, matches: &[usize], options: &RenderOptions) -> std::io::Result<()> {
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    for &line_no in matches {
+        if options.line_numbers {
+            write!(file, "{}\t", original_line_no(line_no, options) + 1)?;
+        }
+        writeln!(file, "{}", lines.line(line_no))?;
+    }
+    file.flush()
+}
+
+// Searches work on a copy of the lines so the reader isn't held up while the query's being typed, and the matches stay
+// in step with what was searched even if more lines come in meanwhile. Lines in memory are shared rather than copied,
+// and an indexed source's copy only has to remember how many lines it has
+fn search_snapshot(source: &Source, filter: &mut Option<Filter>, options: &RenderOptions) -> (Lines, Option<Vec<usize>>) {
+    let lines = source.lines.lock().expect("Could not take lock in search_snapshot");
+    let (lines, _) = filtered_view(&lines, filter, options);
+    (lines.lines.clone(), lines.line_nos.map(|line_nos| line_nos.to_vec()))
+}
+
+// The match status only covers the snapshot that was searched, so say if there's more to the source by now
+fn write_lines_since_search(search: &LastSearch, matches: &[usize], n_searched: usize, source: &Source, filter: &mut Option<Filter>, options: &RenderOptions, search_view_options: &RenderOptions) {
+    let Some(match_no) = search.line_no.and_then(|line_no| matches.iter().position(|&n| n == line_no)) else {
+        return;
+    };
+    let lines = source.lines.lock().expect("Could not take lock in write_lines_since_search");
+    let (lines, _) = filtered_view(&lines, filter, options);
+    if lines.len() > n_searched {
+        write_status_message(&format!("{}, {} new lines since the search", match_status(matches, match_no, search_view_options), lines.len() - n_searched), options);
+    }
+}
+
+// Jumps to the next match after the one we were last on, or after the top of the screen if we've scrolled away from it
+// Returns the line we ended up on
+fn repeat_search(lines: View, matches: &Matches, pos: &mut Option<usize>, last_line_no: Option<usize>, backward: bool, options: &RenderOptions) -> Option<usize> {
+    if matches.is_empty() {
+        overwrite_last_n_lines(lines, *pos, None, matches, options);
+        write_status_message("Pattern not found", options);
+        return None;
+    }
+
+    let (_, rows) = options.size;
+    let top = screen_top(*pos, lines.len(), options);
+    let origin = match last_line_no {
+        Some(line_no) if line_no >= top && line_no < top + rows as usize => line_no,
+        _ => top,
+    };
+
+    let next_match_no = if backward {
+        matches.iter().rposition(|&line_no| line_no < origin)
+    } else {
+        matches.iter().position(|&line_no| line_no > origin)
+    };
+    let match_no = next_match_no.unwrap_or(if backward { matches.len() - 1 } else { 0 });
+
+    let _ = jump_to_match(lines, matches, pos, jump_offset(options), match_no, options);
+    if next_match_no.is_none() {
+        write_status_message(&wrapped_status(matches, match_no, backward, options), options);
+    }
+    Some(matches[match_no])
+}
+
+// A stored position can outlive the lines it pointed at, e.g. if a watched file shrinks
+fn clamp_pos(pos: Option<usize>, n_lines: usize) -> Option<usize> {
+    pos.map(|n| n.min(n_lines.saturating_sub(1)))
+}
+
+// The first line on screen, which for autoscroll depends on the terminal height
+fn screen_top(pos: Option<usize>, n_lines: usize, options: &RenderOptions) -> usize {
+    let (_, rows) = options.size;
+    pos.unwrap_or(n_lines.saturating_sub(rows as usize - 1))
+}
+
+// Like search mode, this ignores everything but keys and resizes until it's closed, the caller redraws afterwards.
+// A resize is kept in the caller's options too, for that redraw
+fn show_help(term_rx: &mpsc::Receiver<TerminalThreadMessage>, caller_options: &mut RenderOptions) {
+    let help = Lines::Loaded(HELP.iter().map(|&line| Arc::from(line)).collect());
+    let help = View { lines: &help, line_nos: None };
+    let mut options = RenderOptions { line_numbers: false, line_nos: None, top_segment: (0, 0), ..*caller_options };
+    let mut pos = Some(0);
+
+    overwrite_last_n_lines(help, pos, None, &Matches::default(), &options);
     loop {
         match term_rx.recv() {
             Ok(TerminalThreadMessage::KeyEvent(event)) => {
                 if event.kind != KeyEventKind::Press {
                     continue;
                 }
-                match event.code {
-                    crossterm::event::KeyCode::Char(c) if c.is_numeric() => {
-                        line_no.push(c);
-                        write_status_message(&format!("Go to line: {}", line_no));
-                    }
-                    crossterm::event::KeyCode::Char('g') | crossterm::event::KeyCode::Char('G') => {
-                        return Some(0);
-                    }
-                    crossterm::event::KeyCode::Backspace => {
-                        if line_no.len() > 0 {
-                            line_no.pop();
-                        } else {
-                            return pos;
-                        }
-                        write_status_message(&format!("Go to line: {}", line_no));
-                    }
-                    crossterm::event::KeyCode::Esc => {
-                        return pos;
-                    }
-                    crossterm::event::KeyCode::Enter => {
-                        break;
-                    }
+                let offset = match event.code {
+                    crossterm::event::KeyCode::Up => -1,
+                    crossterm::event::KeyCode::Down => 1,
+                    crossterm::event::KeyCode::PageUp => -scroll_size(&options),
+                    crossterm::event::KeyCode::PageDown => scroll_size(&options),
                     _ => {
+                        return;
                     }
+                };
+                // Hitting the end would switch to autoscroll, which makes no sense for help
+                page_by(help, &mut pos, offset, &Matches::default(), &options);
+                if pos.is_none() {
+                    pos = Some(help.len() - 1);
+                }
+            }
+            Ok(TerminalThreadMessage::Resize(cols, rows)) => {
+                options.size = usable_size(cols, rows);
+                caller_options.size = options.size;
+                overwrite_last_n_lines(help, pos, None, &Matches::default(), &options);
+            }
+            Ok(_) => {
+                continue;
+            }
+            Err(_) => {
+                return;
+            }
+        }
+    }
+}
+
+// A list of the sources to pick one from, with the current one selected to start with. Like help, this ignores
+// everything but keys and resizes until it's closed, and the caller redraws afterwards (at the new size, if there was
+// a resize). None means the user cancelled
+fn pick_source(sources: &[Arc<Source>], source_index: usize, term_rx: &mpsc::Receiver<TerminalThreadMessage>, caller_options: &mut RenderOptions) -> Option<usize> {
+    let list = Lines::Loaded(sources.iter().map(|source| {
+        let n_lines = source.lines.lock().expect("Could not take lock in pick_source").len();
+        Arc::from(format!("{}  ({} lines)", source.name, n_lines))
+    }).collect());
+    let list = View { lines: &list, line_nos: None };
+    let mut options = RenderOptions { line_numbers: false, chop_long_lines: true, header: 0, line_nos: None, ..*caller_options };
+    let mut selected = source_index;
+    let mut top = 0;
+
+    loop {
+        // Keep the selection on screen, above the status bar
+        let (_, rows) = options.size;
+        let n_rows = (rows as usize - 1).max(1);
+        top = top.min(selected).max((selected + 1).saturating_sub(n_rows));
+        overwrite_last_n_lines(list, Some(top), Some(selected), &Matches::default(), &options);
+        write_status_message("Pick a source: Up/Down to choose, Enter to view, Esc to cancel", &options);
+
+        match term_rx.recv() {
+            Ok(TerminalThreadMessage::KeyEvent(event)) => {
+                if event.kind != KeyEventKind::Press {
+                    continue;
+                }
+                match event.code {
+                    crossterm::event::KeyCode::Up | crossterm::event::KeyCode::Char('k') => selected = selected.saturating_sub(1),
+                    crossterm::event::KeyCode::Down | crossterm::event::KeyCode::Char('j') => selected = (selected + 1).min(list.len() - 1),
+                    crossterm::event::KeyCode::PageUp => selected = selected.saturating_sub(n_rows),
+                    crossterm::event::KeyCode::PageDown => selected = (selected + n_rows).min(list.len() - 1),
+                    crossterm::event::KeyCode::Home => selected = 0,
+                    crossterm::event::KeyCode::End => selected = list.len() - 1,
+                    crossterm::event::KeyCode::Enter => return Some(selected),
+                    crossterm::event::KeyCode::Esc | crossterm::event::KeyCode::Char('q') => return None,
+                    _ => {}
+                }
+            }
+            Ok(TerminalThreadMessage::Resize(cols, rows)) => {
+                options.size = usable_size(cols, rows);
+                caller_options.size = options.size;
+            }
+            Ok(_) => {
+                continue;
+            }
+            Err(_) => {
+                return None;
+            }
+        }
+    }
+}
+
+// Reads a line typed at the status bar, None means the user cancelled
+fn read_prompt(prompt: &str, term_rx: &mpsc::Receiver<TerminalThreadMessage>, options: &RenderOptions) -> Option<String> {
+    let mut query = String::new();
+    let mut cursor = 0;
+    write_input_status(prompt, &query, cursor, "", options);
+    loop {
+        match read_line_input(&mut query, &mut cursor, |_| true, term_rx) {
+            InputKey::Edited => write_input_status(prompt, &query, cursor, "", options),
+            InputKey::Submit => return Some(query.trim().to_string()),
+            InputKey::Cancel => return None,
+            InputKey::Other(_) => {}
+        }
+    }
+}
+
+// What a key did to a line being typed in at the status bar
+enum InputKey {
+    Edited,
+    Submit,
+    Cancel,
+    // Anything that isn't for editing is left to the prompt, e.g. Ctrl-S in a search
+    Other(crossterm::event::KeyEvent),
+}
+
+// Waits for a key and applies it to a line being typed at a prompt, see edit_line
+fn read_line_input(input: &mut String, cursor: &mut usize, accept: impl Fn(char) -> bool, term_rx: &mpsc::Receiver<TerminalThreadMessage>) -> InputKey {
+    loop {
+        let Ok(TerminalThreadMessage::KeyEvent(event)) = term_rx.recv() else {
+            continue;
+        };
+        if event.kind != KeyEventKind::Press {
+            continue;
+        }
+        return edit_line(input, cursor, event, &accept);
+    }
+}
+
+// Applies a key to a line being typed at a prompt. The cursor is a byte offset into the line
+// Left/Right, Home/End (or Ctrl-A/Ctrl-E) move the cursor, Backspace, Delete and Ctrl-W (the word before the cursor) delete,
+// and chars that `accept` allows are inserted. Like less, Backspace on an empty line cancels
+fn edit_line(input: &mut String, cursor: &mut usize, event: crossterm::event::KeyEvent, accept: impl Fn(char) -> bool) -> InputKey {
+    let prev_char = input[..*cursor].chars().next_back().map_or(0, |c| c.len_utf8());
+    let next_char = input[*cursor..].chars().next().map_or(0, |c| c.len_utf8());
+    let ctrl = event.modifiers.contains(KeyModifiers::CONTROL);
+    match event.code {
+        crossterm::event::KeyCode::Enter => return InputKey::Submit,
+        crossterm::event::KeyCode::Esc => return InputKey::Cancel,
+        crossterm::event::KeyCode::Backspace if input.is_empty() => return InputKey::Cancel,
+        crossterm::event::KeyCode::Backspace => {
+            // Nothing before the cursor to delete
+            if prev_char > 0 {
+                *cursor -= prev_char;
+                input.remove(*cursor);
+            }
+        }
+        crossterm::event::KeyCode::Delete => {
+            if next_char > 0 {
+                input.remove(*cursor);
+            }
+        }
+        crossterm::event::KeyCode::Left => *cursor -= prev_char,
+        crossterm::event::KeyCode::Right => *cursor += next_char,
+        crossterm::event::KeyCode::Home => *cursor = 0,
+        crossterm::event::KeyCode::End => *cursor = input.len(),
+        crossterm::event::KeyCode::Char('a') if ctrl => *cursor = 0,
+        crossterm::event::KeyCode::Char('e') if ctrl => *cursor = input.len(),
+        crossterm::event::KeyCode::Char('w') if ctrl => {
+            let before = input[..*cursor].trim_end();
+            // The word starts after the whitespace before it, which isn't always a single byte
+            let word_start = before.char_indices().rfind(|(_, c)| c.is_whitespace()).map_or(0, |(i, c)| i + c.len_utf8());
+            input.replace_range(word_start..*cursor, "");
+            *cursor = word_start;
+        }
+        crossterm::event::KeyCode::Char(c) if !ctrl && accept(c) => {
+            input.insert(*cursor, c);
+            *cursor += c.len_utf8();
+        }
+        _ => return InputKey::Other(event),
+    }
+    InputKey::Edited
+}
+
+// Shows a prompt and what's been typed so far, with the char under the cursor in reverse video as a caret
+fn write_input_status(prompt: &str, input: &str, cursor: usize, suffix: &str, options: &RenderOptions) {
+    let (before, after) = input.split_at(cursor);
+    let mut after = after.chars();
+    let under_cursor = after.next().unwrap_or(' ');
+    write_status_message(&format!("{}: {}\x1b[7m{}\x1b[27m{}{}", prompt, before, under_cursor, after.as_str(), suffix), options);
+}
+
+// Sources that look binary are only shown once the user says so, the first time they come up
+fn is_viewable(sources: &[Arc<Source>], source_index: usize, viewable_by_source: &mut [Option<bool>], term_rx: &mpsc::Receiver<TerminalThreadMessage>, options: &RenderOptions) -> bool {
+    *viewable_by_source[source_index].get_or_insert_with(|| {
+        write_status_message(&format!("\"{}\" may be a binary file -- view anyway? (y/n)", sources[source_index].name), options);
+        loop {
+            if let Ok(TerminalThreadMessage::KeyEvent(event)) = term_rx.recv() {
+                if event.kind != KeyEventKind::Press {
+                    continue;
+                }
+                match event.code {
+                    crossterm::event::KeyCode::Char('y') | crossterm::event::KeyCode::Char('Y') => return true,
+                    crossterm::event::KeyCode::Char('n') | crossterm::event::KeyCode::Char('N') | crossterm::event::KeyCode::Esc => return false,
+                    _ => {}
                 }
-            },
-            _ => {
+            }
+        }
+    })
+}
+
+// The first source from source_index on that can be shown, skipping any the user didn't want to see
+fn next_viewable(sources: &[Arc<Source>], source_index: usize, viewable_by_source: &mut [Option<bool>], term_rx: &mpsc::Receiver<TerminalThreadMessage>, options: &RenderOptions) -> Option<usize> {
+    (0..sources.len()).map(|n| (source_index + n) % sources.len()).find(|&i| is_viewable(sources, i, viewable_by_source, term_rx, options))
+}
+
+// Waits up to timeout for the next message after the first key of a two key sequence, None if nothing came
+// Keys being released don't count
+fn next_message_within(term_rx: &mpsc::Receiver<TerminalThreadMessage>, timeout: Duration) -> Option<TerminalThreadMessage> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match term_rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(TerminalThreadMessage::KeyEvent(event)) if event.kind != KeyEventKind::Press => {}
+            Ok(message) => return Some(message),
+            Err(_) => return None,
+        }
+    }
+}
+
+// Waits for the letter naming a mark, Esc or any other key cancels
+fn read_mark_name(prompt: &str, term_rx: &mpsc::Receiver<TerminalThreadMessage>, options: &RenderOptions) -> Option<char> {
+    write_status_message(prompt, options);
+    loop {
+        if let Ok(TerminalThreadMessage::KeyEvent(event)) = term_rx.recv() {
+            if event.kind != KeyEventKind::Press {
                 continue;
             }
+            return match event.code {
+                crossterm::event::KeyCode::Char(c) if c.is_alphabetic() => Some(c),
+                _ => None,
+            };
+        }
+    }
+}
+
+// Also returns a message to show once we've moved, if the line wasn't exactly where we ended up
+fn handle_go_to_line(pos: Option<usize>, n_lines: usize, term_rx: &mpsc::Receiver<TerminalThreadMessage>, options: &RenderOptions) -> (Option<usize>, Option<String>) {
+    let mut line_no = String::new();
+    let mut cursor = 0;
+    write_input_status("Go to line", &line_no, cursor, "", options);
+    loop {
+        // Anything else (e.g. pasted along with the number) is ignored
+        match read_line_input(&mut line_no, &mut cursor, |c| c.is_ascii_digit(), term_rx) {
+            InputKey::Edited => write_input_status("Go to line", &line_no, cursor, "", options),
+            InputKey::Submit => break,
+            InputKey::Cancel => return (pos, None),
+            InputKey::Other(event) if matches!(event.code, crossterm::event::KeyCode::Char('g') | crossterm::event::KeyCode::Char('G')) => {
+                return (Some(0), None);
+            }
+            InputKey::Other(_) => {}
         }
     }
 
+    go_to_line(&line_no, pos, n_lines)
+}
+
+// Where typing line_no in at the go to line prompt takes us, and what to tell the user about it if anything
+fn go_to_line(line_no: &str, pos: Option<usize>, n_lines: usize) -> (Option<usize>, Option<String>) {
     let line_no = line_no.trim();
-    if line_no.len() == 0 {
-        return pos;
+    if line_no.is_empty() {
+        return (pos, None);
     }
 
-    let line_no = line_no.parse::<usize>().expect("Could not parse line number");
+    // Only digits get typed in, so this can only fail on a number too big for a usize
+    let Ok(line_no) = line_no.parse::<usize>() else {
+        return (pos, Some("invalid line number".to_string()));
+    };
+    // Going past the end stays put on the last line, None (i.e. autoscroll) is only for when that's asked for with G
     if line_no > n_lines {
-        None
+        (Some(n_lines.saturating_sub(1)), Some(format!("line {} > end, jumped to last line", line_no)))
     } else if line_no == 0 {
-        Some(0)
+        (Some(0), None)
     } else {
-        Some(line_no - 1)
+        (Some(line_no - 1), None)
     }
 }
 
+// How far down the screen a line that's jumped to goes, the middle unless --jump-target says otherwise
+fn jump_offset(options: &RenderOptions) -> usize {
+    let (_, rows) = options.size;
+    jump_row(rows as usize, options)
+}
+
+// Like vim's scrolloff, a line that's jumped to is kept at least --scrolloff rows from the top and bottom of the screen
+// where there's room. Near the start or end of the input there's only as much as there is
+fn jump_row(rows: usize, options: &RenderOptions) -> usize {
+    let text_rows = rows.saturating_sub(1).max(1);
+    let row = options.jump_target.unwrap_or(text_rows / 2);
+    if options.scrolloff * 2 >= text_rows {
+        return (text_rows - 1) / 2;
+    }
+    row.clamp(options.scrolloff, text_rows - 1 - options.scrolloff)
+}
+
 fn pos_with_in_view(pos: Option<usize>, page_up_size: usize) -> Option<usize> {
     if let Some(n) = pos {
         if n >= page_up_size {
@@ -418,41 +2342,489 @@ fn pos_with_in_view(pos: Option<usize>, page_up_size: usize) -> Option<usize> {
 }
 
 fn get_pos(pos: Option<usize>, n_lines: usize, n_rows: usize, requested_offset: i32) -> Option<usize> {
+    let offset = requested_offset.unsigned_abs() as usize;
     if requested_offset == 0 {
         pos
     } else if requested_offset > 0 {
-        if let Some(mut n) = pos {
-            n += requested_offset as usize;
-
-            if n >= n_lines {
-                return None;
-            }
-
-            Some(n)
-        } else {
-            None
+        let n = pos?.saturating_add(offset);
+        if n >= n_lines {
+            return None;
         }
+        Some(n)
     } else {
-        if let Some(n) = pos {
-            if n < -requested_offset as usize {
-                return Some(0);
-            }
-            Some(n - (-requested_offset as usize))
-        } else {
-            if n_lines < -requested_offset as usize {
-                return Some(0);
+        // Going back from autoscroll starts from the top of the screen, and nothing goes back past the first line
+        let top = pos.unwrap_or(n_lines.saturating_sub(n_rows));
+        Some(top.saturating_sub(offset))
+    }
+}
+
+// How far d, u, PgDn and PgUp move, half a screen unless it's been set with --scroll or a count
+// This and full_page_size are queried on every press so that paging adapts to resizes
+fn scroll_size(options: &RenderOptions) -> i32 {
+    let (_, rows) = options.size;
+    options.scroll.map_or(rows as i32 / 2, |n| n.min(i32::MAX as usize) as i32).max(1)
+}
+
+// How far a single-line motion moves, 1 unless a count was typed before it
+fn count_offset(count: Option<usize>) -> i32 {
+    count.unwrap_or(1).min(i32::MAX as usize) as i32
+}
+
+// How far Space and b move, a whole screen of lines, i.e. everything above the status bar
+fn full_page_size(options: &RenderOptions) -> i32 {
+    let (_, rows) = options.size;
+    (rows as i32 - 1).max(1)
+}
+
+// Like page_by, but with wrapping a line that wraps onto several rows is scrolled a row at a time, like less. Returns
+// the new top_segment for the options, going past the end goes into autoscroll as page_by does
+fn scroll_rows(lines: View, pos: &mut Option<usize>, offset: i32, match_lines: &Matches, options: &RenderOptions) -> (usize, usize) {
+    if options.chop_long_lines {
+        page_by(lines, pos, offset, match_lines, options);
+        return (0, 0);
+    }
+
+    let (cols, rows) = options.size;
+    let (header, text_cols, text_rows) = text_area(lines, cols as usize, rows as usize, options);
+    let tail = screen_start(lines, None, header, text_cols, text_rows, options);
+    let (mut line_no, mut skip) = screen_start(lines, *pos, header, text_cols, text_rows, options);
+    for _ in 0..offset.unsigned_abs() {
+        if offset > 0 {
+            if (line_no, skip) >= tail {
+                *pos = None;
+                break;
             }
-            if n_lines < n_rows {
-                return Some(n_lines - (-requested_offset as usize));
+            if skip + 1 < line_rows(&lines.line(line_no), text_cols, options) {
+                skip += 1;
+            } else {
+                line_no += 1;
+                skip = 0;
             }
-            return Some(n_lines - n_rows - (-requested_offset as usize))
+        } else if skip > 0 {
+            skip -= 1;
+        } else if line_no > header {
+            line_no -= 1;
+            skip = line_rows(&lines.line(line_no), text_cols, options) - 1;
         }
+        *pos = Some(line_no);
     }
+
+    let top_segment = (line_no, skip);
+    overwrite_last_n_lines(lines, *pos, None, match_lines, &RenderOptions { top_segment, ..*options });
+    top_segment
 }
 
-fn page_by(lines: &Vec<String>, pos: &mut Option<usize>, offset: i32) {
-    let (_, rows) = crossterm::terminal::size().expect("Could not get terminal size");
+fn page_by(lines: View, pos: &mut Option<usize>, offset: i32, match_lines: &Matches, options: &RenderOptions) {
+    let (_, rows) = options.size;
     *pos = get_pos(*pos, lines.len(), rows as usize, offset);
 
-    overwrite_last_n_lines(&lines, *pos, None);
+    overwrite_last_n_lines(lines, *pos, None, match_lines, options);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_pos_going_back_from_autoscroll_stops_at_the_start() {
+        // 30 lines on a 24 row terminal, G then b
+        assert_eq!(get_pos(None, 30, 24, -23), Some(0));
+        assert_eq!(get_pos(None, 30, 24, -3), Some(3));
+        assert_eq!(get_pos(None, 10, 24, -5), Some(0));
+        assert_eq!(get_pos(None, 0, 24, -1), Some(0));
+    }
+
+    #[test]
+    fn get_pos_going_back_stops_at_the_start() {
+        assert_eq!(get_pos(Some(5), 30, 24, -10), Some(0));
+        assert_eq!(get_pos(Some(5), 30, 24, -5), Some(0));
+        assert_eq!(get_pos(Some(5), 30, 24, -2), Some(3));
+        assert_eq!(get_pos(Some(5), 30, 24, i32::MIN), Some(0));
+    }
+
+    #[test]
+    fn get_pos_going_past_the_end_autoscrolls() {
+        assert_eq!(get_pos(Some(5), 30, 24, 10), Some(15));
+        assert_eq!(get_pos(Some(5), 30, 24, 25), None);
+        assert_eq!(get_pos(Some(usize::MAX), 30, 24, 1), None);
+        assert_eq!(get_pos(None, 30, 24, 1), None);
+        assert_eq!(get_pos(Some(5), 30, 24, 0), Some(5));
+        assert_eq!(get_pos(None, 30, 24, 0), None);
+    }
+
+    fn type_keys(input: &mut String, cursor: &mut usize, keys: &[(crossterm::event::KeyCode, KeyModifiers)]) {
+        for &(code, modifiers) in keys {
+            edit_line(input, cursor, crossterm::event::KeyEvent::new(code, modifiers), |_| true);
+        }
+    }
+
+    #[test]
+    fn backspace_at_the_start_of_a_prompt_does_nothing() {
+        let (mut input, mut cursor) = (String::new(), 0);
+        let keys = "abc".chars().map(|c| (crossterm::event::KeyCode::Char(c), KeyModifiers::NONE))
+            .chain([(crossterm::event::KeyCode::Home, KeyModifiers::NONE), (crossterm::event::KeyCode::Backspace, KeyModifiers::NONE)])
+            .collect::<Vec<_>>();
+        type_keys(&mut input, &mut cursor, &keys);
+        assert_eq!(input, "abc");
+        assert_eq!(cursor, 0);
+    }
+
+    #[test]
+    fn backspace_deletes_the_char_before_the_cursor() {
+        let (mut input, mut cursor) = ("héllo".to_string(), 3);
+        type_keys(&mut input, &mut cursor, &[(crossterm::event::KeyCode::Backspace, KeyModifiers::NONE)]);
+        assert_eq!(input, "hllo");
+        assert_eq!(cursor, 1);
+    }
+
+    #[test]
+    fn backspace_on_an_empty_prompt_cancels() {
+        let (mut input, mut cursor) = (String::new(), 0);
+        let key = crossterm::event::KeyEvent::new(crossterm::event::KeyCode::Backspace, KeyModifiers::NONE);
+        assert!(matches!(edit_line(&mut input, &mut cursor, key, |_| true), InputKey::Cancel));
+    }
+
+    #[test]
+    fn ctrl_w_deletes_the_word_before_the_cursor() {
+        let (mut input, mut cursor) = ("foo bar  ".to_string(), 9);
+        type_keys(&mut input, &mut cursor, &[(crossterm::event::KeyCode::Char('w'), KeyModifiers::CONTROL)]);
+        assert_eq!(input, "foo ");
+        assert_eq!(cursor, 4);
+    }
+
+    #[test]
+    fn ctrl_w_after_multibyte_whitespace() {
+        // U+3000 is an ideographic space, three bytes long
+        let mut input = "foo\u{3000}bar".to_string();
+        let mut cursor = input.len();
+        type_keys(&mut input, &mut cursor, &[(crossterm::event::KeyCode::Char('w'), KeyModifiers::CONTROL)]);
+        assert_eq!(input, "foo\u{3000}");
+        assert_eq!(cursor, input.len());
+    }
+
+    #[test]
+    fn typing_inserts_at_the_cursor() {
+        let (mut input, mut cursor) = ("ac".to_string(), 1);
+        type_keys(&mut input, &mut cursor, &[(crossterm::event::KeyCode::Char('b'), KeyModifiers::NONE), (crossterm::event::KeyCode::Right, KeyModifiers::NONE), (crossterm::event::KeyCode::Char('é'), KeyModifiers::NONE)]);
+        assert_eq!(input, "abcé");
+        assert_eq!(cursor, input.len());
+    }
+
+    #[test]
+    fn go_to_line_past_the_end_jumps_to_the_last_line() {
+        assert_eq!(go_to_line("20", Some(3), 10), (Some(9), Some("line 20 > end, jumped to last line".to_string())));
+        assert_eq!(go_to_line("10", Some(3), 10), (Some(9), None));
+        assert_eq!(go_to_line("1", None, 10), (Some(0), None));
+        assert_eq!(go_to_line("0", None, 10), (Some(0), None));
+        assert_eq!(go_to_line("", Some(3), 10), (Some(3), None));
+    }
+
+    #[test]
+    fn go_to_a_line_number_too_big_for_a_usize() {
+        assert_eq!(go_to_line("999999999999999999999", Some(3), 10), (Some(3), Some("invalid line number".to_string())));
+    }
+
+    #[test]
+    fn smartcase_is_only_case_sensitive_with_an_uppercase_letter() {
+        let search_options = SearchOptions { case_sensitive: true, whole_word: false, smartcase: true, history: Vec::new() };
+        assert!(!is_case_sensitive("error", &search_options));
+        assert!(is_case_sensitive("Error", &search_options));
+
+        let lines = to_lines(&["error", "Error", "ERROR"]);
+        let matches = |search| get_matches(view(&lines), search, false, is_case_sensitive(search, &search_options), false).line_nos;
+        assert_eq!(matches("error"), [0, 1, 2]);
+        assert_eq!(matches("Error"), [1]);
+        // Without it the case_sensitive setting is used as it is
+        let search_options = SearchOptions { smartcase: false, ..search_options };
+        assert!(is_case_sensitive("error", &search_options));
+    }
+
+    fn plain_options(cols: u16, rows: u16) -> RenderOptions<'static> {
+        RenderOptions {
+            line_numbers: false,
+            color: false,
+            tab_width: 8,
+            chop_long_lines: false,
+            word_wrap: false,
+            squeeze_blank: false,
+            raw_control_chars: false,
+            overstrike: false,
+            show_whitespace: false,
+            scroll: None,
+            jump_target: None,
+            scrolloff: 0,
+            header: 0,
+            line_nos: None,
+            top_segment: (0, 0),
+            dropped_lines: 0,
+            size: (cols, rows),
+            ui_color: false,
+            theme: theme::DEFAULT_THEME,
+        }
+    }
+
+    fn view(lines: &Lines) -> View<'_> {
+        View { lines, line_nos: None }
+    }
+
+    fn to_lines(lines: &[&str]) -> Lines {
+        Lines::Loaded(lines.iter().map(|&line| Arc::from(line)).collect())
+    }
+
+    fn numbered_lines(n: usize) -> Lines {
+        Lines::Loaded((1..=n).map(|i| Arc::from(i.to_string())).collect())
+    }
+
+    #[test]
+    fn render_wraps_long_lines() {
+        // 5 rows is 4 for text and the status bar
+        let lines = to_lines(&["abcdefghijklmnopqrstuvwxy", "z"]);
+        let screen = render_screen(view(&lines), Some(0), None, &Matches::default(), &plain_options(10, 5));
+        assert_eq!(screen.rows, ["abcdefghij", "klmnopqrst", "uvwxy", "z"]);
+        assert_eq!((screen.start, screen.last_displayed_line), (0, Some(1)));
+    }
+
+    #[test]
+    fn render_wraps_multibyte_lines_between_chars() {
+        // Two bytes each, so a split by bytes would land in the middle of one
+        let lines = to_lines(&["éééééééééééééé", "😀é😀é😀é😀é😀é😀é"]);
+        let screen = render_screen(view(&lines), Some(0), None, &Matches::default(), &plain_options(5, 8));
+        assert_eq!(screen.rows, ["ééééé", "ééééé", "éééé", "😀é😀", "é😀é", "😀é😀", "é😀é"]);
+        let options = RenderOptions { chop_long_lines: true, ..plain_options(5, 8) };
+        assert_eq!(render_screen(view(&lines), Some(0), None, &Matches::default(), &options).rows, ["éééé>", "😀é>"]);
+    }
+
+    #[test]
+    fn render_word_wraps_long_lines() {
+        let lines = to_lines(&["the quick brown fox", "abcdefghijklmn"]);
+        let options = RenderOptions { word_wrap: true, ..plain_options(10, 8) };
+        let screen = render_screen(view(&lines), Some(0), None, &Matches::default(), &options);
+        assert_eq!(screen.rows, ["the quick ", "brown fox", "abcdefghij", "klmn"]);
+    }
+
+    #[test]
+    fn render_chops_long_lines_with_a_marker() {
+        let lines = to_lines(&["abcdefghijklmnopqrstuvwxy", "z"]);
+        let options = RenderOptions { chop_long_lines: true, ..plain_options(10, 5) };
+        let screen = render_screen(view(&lines), Some(0), None, &Matches::default(), &options);
+        assert_eq!(screen.rows, ["abcdefghi>", "z"]);
+    }
+
+    #[test]
+    fn render_numbers_only_the_first_row_of_a_wrapped_line() {
+        let lines = to_lines(&["abcdefghijkl", "m"]);
+        let options = RenderOptions { line_numbers: true, ..plain_options(10, 5) };
+        let screen = render_screen(view(&lines), Some(0), None, &Matches::default(), &options);
+        assert_eq!(screen.rows, ["1 abcdefgh", "  ijkl", "2 m"]);
+    }
+
+    #[test]
+    fn render_autoscroll_shows_the_end() {
+        let lines = numbered_lines(10);
+        let screen = render_screen(view(&lines), None, None, &Matches::default(), &plain_options(10, 5));
+        assert_eq!(screen.rows, ["7", "8", "9", "10"]);
+        assert_eq!((screen.start, screen.last_displayed_line), (6, Some(9)));
+    }
+
+    #[test]
+    fn render_scrolls_to_pos() {
+        let lines = numbered_lines(10);
+        let screen = render_screen(view(&lines), Some(3), None, &Matches::default(), &plain_options(10, 5));
+        assert_eq!(screen.rows, ["4", "5", "6", "7"]);
+        assert_eq!((screen.start, screen.last_displayed_line), (3, Some(6)));
+    }
+
+    #[test]
+    fn render_pulls_a_pos_near_the_end_back_to_fill_the_screen() {
+        let lines = numbered_lines(10);
+        let screen = render_screen(view(&lines), Some(8), None, &Matches::default(), &plain_options(10, 5));
+        assert_eq!(screen.rows, ["7", "8", "9", "10"]);
+    }
+
+    #[test]
+    fn a_pos_past_the_end_is_clamped_to_the_last_line() {
+        assert_eq!(clamp_pos(Some(90), 5), Some(4));
+        assert_eq!(clamp_pos(Some(2), 5), Some(2));
+        assert_eq!(clamp_pos(Some(3), 0), Some(0));
+        assert_eq!(clamp_pos(None, 5), None);
+        // So switching to a shorter source shows its end rather than nothing
+        let lines = numbered_lines(5);
+        let screen = render_screen(view(&lines), clamp_pos(Some(90), lines.len()), None, &Matches::default(), &plain_options(10, 5));
+        assert_eq!(screen.rows, ["2", "3", "4", "5"]);
+    }
+
+    #[test]
+    fn render_a_pos_past_the_end() {
+        // e.g. a watched file that was truncated under us
+        let lines = numbered_lines(10);
+        let screen = render_screen(view(&lines), Some(50), None, &Matches::default(), &plain_options(10, 5));
+        assert_eq!(screen.rows, ["7", "8", "9", "10"]);
+        assert_eq!(screen.last_displayed_line, Some(9));
+    }
+
+    #[test]
+    fn render_a_zero_sized_terminal() {
+        assert_eq!(usable_size(0, 0), (1, 2));
+        let lines = to_lines(&["abc", "d"]);
+        let screen = render_screen(view(&lines), Some(0), None, &Matches::default(), &plain_options(0, 0));
+        assert_eq!(screen.rows, ["a"]);
+    }
+
+    #[test]
+    fn render_fewer_lines_than_rows() {
+        let lines = numbered_lines(2);
+        let screen = render_screen(view(&lines), None, None, &Matches::default(), &plain_options(10, 5));
+        assert_eq!(screen.rows, ["1", "2"]);
+        assert_eq!((screen.start, screen.last_displayed_line), (0, Some(1)));
+    }
+
+    #[test]
+    fn render_nothing_read_yet() {
+        let screen = render_screen(view(&to_lines(&[])), None, None, &Matches::default(), &plain_options(10, 5));
+        assert!(screen.rows.is_empty());
+        assert_eq!((screen.start, screen.last_displayed_line), (0, None));
+        assert_eq!(position_status(screen.start, screen.last_displayed_line, 0, None), "lines - / 0 (END)");
+    }
+
+    #[test]
+    fn render_autoscroll_shows_the_end_of_a_line_taller_than_the_screen() {
+        let lines = to_lines(&["1", "abcdefghijklmnopqrstuvwxyz"]);
+        let screen = render_screen(view(&lines), None, None, &Matches::default(), &plain_options(5, 4));
+        assert_eq!(screen.rows, ["pqrst", "uvwxy", "z"]);
+        assert_eq!(screen.last_displayed_line, Some(1));
+    }
+
+    #[test]
+    fn render_a_line_longer_than_the_whole_screen() {
+        let long = "x".repeat(100);
+        let options = plain_options(10, 5);
+        assert_eq!(wrapped_row_count(&long, 10, &options), 10);
+        assert_eq!(wrapped_row_count(&long, 10, &RenderOptions { chop_long_lines: true, ..options }), 1);
+        // Only as much of it as fits above the status bar is drawn
+        let lines = to_lines(&[&long, "after"]);
+        let screen = render_screen(view(&lines), Some(0), None, &Matches::default(), &options);
+        assert_eq!(screen.rows, ["xxxxxxxxxx"; 4]);
+        assert_eq!(screen.last_displayed_line, Some(0));
+    }
+
+    #[test]
+    fn render_skips_the_rows_of_a_partly_scrolled_top_line() {
+        let lines = to_lines(&["abcdefghijklmnopqrstuvwxy", "z", "1", "2"]);
+        let options = RenderOptions { top_segment: (0, 1), ..plain_options(10, 5) };
+        assert_eq!(render_screen(view(&lines), Some(0), None, &Matches::default(), &options).rows, ["klmnopqrst", "uvwxy", "z", "1"]);
+        // Only while that line is at the top
+        let lines = to_lines(&["0", "abcdefghijklmnopqrstuvwxy", "z", "1", "2"]);
+        assert_eq!(render_screen(view(&lines), Some(1), None, &Matches::default(), &options).rows, ["abcdefghij", "klmnopqrst", "uvwxy", "z"]);
+        // Nor past where the end of the input fills the screen
+        let lines = to_lines(&["abcdefghijklmnopqrstuvwxy", "z"]);
+        assert_eq!(render_screen(view(&lines), Some(0), None, &Matches::default(), &options).rows, ["abcdefghij", "klmnopqrst", "uvwxy", "z"]);
+    }
+
+    #[test]
+    fn render_keeps_the_header_at_the_top() {
+        let lines = numbered_lines(10);
+        let options = RenderOptions { header: 1, ..plain_options(10, 5) };
+        let screen = render_screen(view(&lines), Some(5), None, &Matches::default(), &options);
+        assert_eq!(screen.rows, ["1", "6", "7", "8"]);
+    }
+
+    #[test]
+    fn render_highlights_without_ui_color() {
+        let lines = to_lines(&["a", "b"]);
+        let screen = render_screen(view(&lines), Some(0), Some(1), &Matches::default(), &plain_options(10, 5));
+        assert_eq!(screen.rows, ["a", "\x1b[7mb\x1b[0m"]);
+    }
+
+    #[test]
+    fn render_highlights_in_the_theme_colours() {
+        let lines = to_lines(&["a", "b"]);
+        let theme = Theme { highlight_bg: crossterm::style::Color::Red, highlight_fg: crossterm::style::Color::White, ..theme::DEFAULT_THEME };
+        let options = RenderOptions { ui_color: true, theme, ..plain_options(10, 5) };
+        let screen = render_screen(view(&lines), Some(0), Some(1), &Matches::default(), &options);
+        assert_eq!(screen.rows, ["a", "\x1b[48;5;9m\x1b[38;5;15mb\x1b[0m"]);
+    }
+
+    #[test]
+    fn render_highlights_just_the_matched_part() {
+        let lines = to_lines(&["one two", "three"]);
+        let matches = get_matches(view(&lines), "two", false, true, false);
+        let screen = render_screen(view(&lines), Some(0), None, &matches, &plain_options(10, 5));
+        // The row has escapes in it now, so it gets the reset every such row ends with as well
+        assert_eq!(screen.rows, ["one \x1b[4mtwo\x1b[0m\x1b[0m", "three"]);
+    }
+
+    #[test]
+    fn search_for_something_missing_finds_nothing() {
+        let lines = to_lines(&["one", "two"]);
+        let options = plain_options(10, 5);
+        let matches = get_matches(view(&lines), "three", false, true, false);
+        assert!(matches.is_empty());
+        // Which there's no match to jump to, rather than indexing past the end
+        let (match_no, _) = initial_match_no(&matches, Some(0), lines.len(), SearchKind { regex: false, backward: false });
+        let mut pos = Some(0);
+        assert!(jump_to_match(view(&lines), &matches, &mut pos, jump_offset(&options), match_no, &options).is_err());
+        assert_eq!(pos, Some(0));
+    }
+
+    fn test_source(lines: &[&str]) -> Source {
+        Source {
+            name: "test".to_string(),
+            path: None,
+            reader: None,
+            lines: std::sync::Mutex::new(to_lines(lines)),
+            binary: false,
+            watched: false,
+            bytes_read: std::sync::atomic::AtomicUsize::new(0),
+            dropped: std::sync::atomic::AtomicUsize::new(0),
+        }
+    }
+
+    #[test]
+    fn search_snapshot_is_not_changed_by_lines_read_after_it() {
+        let source = test_source(&["match 1", "other", "match 2"]);
+        let options = plain_options(10, 5);
+        let mut filter = None;
+        let (lines, line_nos) = search_snapshot(&source, &mut filter, &options);
+        if let Lines::Loaded(source_lines) = &mut *source.lines.lock().unwrap() {
+            source_lines.extend(["match 3", "other"].map(Arc::from));
+        }
+
+        assert_eq!(line_nos, None);
+        assert_eq!(get_matches(view(&lines), "match", false, true, false).line_nos, [0, 2]);
+        // The lines in between and after the matches are still there to jump around in
+        assert_eq!(lines.len(), 3);
+        assert_eq!(source.lines.lock().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn search_snapshot_of_a_filter_keeps_its_line_numbers() {
+        let source = test_source(&["a 1", "b", "a 2", "b", "a 3"]);
+        let options = plain_options(10, 5);
+        let mut filter = Some(Filter { query: "a".to_string(), case_sensitive: true, squeeze_blank: false, line_nos: Vec::new(), checked: 0 });
+        let (lines, line_nos) = search_snapshot(&source, &mut filter, &options);
+        assert_eq!(line_nos, Some(vec![0, 2, 4]));
+        let view = View { lines: &lines, line_nos: line_nos.as_deref() };
+        assert_eq!((0..view.len()).map(|i| view.line(i)).collect::<Vec<_>>(), ["a 1", "a 2", "a 3"]);
+    }
+
+    #[test]
+    fn indexed_lines_are_read_back_from_the_file() {
+        let path = std::env::temp_dir().join(format!("lesser-test-{}-render-index", std::process::id()));
+        std::fs::write(&path, "one\ntwo\nthree\n").unwrap();
+        let mut index = crate::reader::line_index::LineIndex::open(&path).expect("Could not index test file");
+        for line in ["one\n", "two\n", "three\n"] {
+            index.add_line(line.len());
+        }
+        let lines = Lines::Indexed(index);
+        let options = plain_options(10, 5);
+        assert_eq!(render_screen(view(&lines), Some(0), None, &Matches::default(), &options).rows, ["one", "two", "three"]);
+
+        let matches = get_matches(view(&lines), "t", false, true, false);
+        assert_eq!(matches.line_nos, [1, 2]);
+        // A filter's lines are looked up by where they are in the source
+        let filtered = View { lines: &lines, line_nos: Some(&matches.line_nos) };
+        let filter_options = RenderOptions { line_nos: Some(&matches.line_nos), ..options };
+        assert_eq!(render_screen(filtered, Some(0), None, &Matches::default(), &filter_options).rows, ["two", "three"]);
+        std::fs::remove_file(path).unwrap();
+    }
 }
@@ -0,0 +1,81 @@
+use crossterm::style::Color;
+use crate::config;
+
+// Colours for everything we draw ourselves (the input's own colours are left alone), set in the config file with
+//
+//   [colors]
+//   highlight-bg = "#005f87"
+//   status-fg = "white"
+//
+// or with the flags of the same name, which win over the config
+#[derive(Clone, Copy)]
+pub struct Theme {
+    // The current search match
+    pub highlight_bg: Color,
+    pub highlight_fg: Color,
+    // Every other search match
+    pub match_bg: Color,
+    pub match_fg: Color,
+    pub status_bg: Color,
+    pub status_fg: Color,
+}
+
+pub const DEFAULT_THEME: Theme = Theme {
+    highlight_bg: Color::Cyan,
+    highlight_fg: Color::Black,
+    match_bg: Color::DarkYellow,
+    match_fg: Color::Black,
+    status_bg: Color::Grey,
+    status_fg: Color::Black,
+};
+
+// Colours that don't parse are warned about and left as the default
+pub fn theme_from_config(config: &toml::Table) -> Theme {
+    let mut theme = DEFAULT_THEME;
+    let Some(colors) = config.get("colors") else {
+        return theme;
+    };
+    let Some(colors) = colors.as_table() else {
+        config::warn("ignoring colors, it should be a table");
+        return theme;
+    };
+
+    for (name, value) in colors {
+        let field = match name.as_str() {
+            "highlight-bg" => &mut theme.highlight_bg,
+            "highlight-fg" => &mut theme.highlight_fg,
+            "match-bg" => &mut theme.match_bg,
+            "match-fg" => &mut theme.match_fg,
+            "status-bg" => &mut theme.status_bg,
+            "status-fg" => &mut theme.status_fg,
+            _ => {
+                config::warn(&format!("ignoring unknown colour setting {}", name));
+                continue;
+            }
+        };
+        match value.as_str().map(parse_color) {
+            Some(Ok(color)) => *field = color,
+            Some(Err(e)) => config::warn(&format!("ignoring {}: {}", name, e)),
+            None => config::warn(&format!("ignoring {}, it should be a colour name", name)),
+        }
+    }
+
+    theme
+}
+
+// A name like dark_grey, a 256 colour number, or #rrggbb
+pub fn parse_color(name: &str) -> Result<Color, String> {
+    let name = name.trim();
+    if let Some(hex) = name.strip_prefix('#') {
+        let channel = |i: usize| hex.get(i..i + 2).and_then(|channel| u8::from_str_radix(channel, 16).ok());
+        return match (hex.len(), channel(0), channel(2), channel(4)) {
+            (6, Some(r), Some(g), Some(b)) => Ok(Color::Rgb { r, g, b }),
+            _ => Err(format!("{} isn't a #rrggbb colour", name)),
+        };
+    }
+    if let Ok(n) = name.parse::<u8>() {
+        return Ok(Color::AnsiValue(n));
+    }
+    // dark-grey works as well as dark_grey
+    Color::try_from(name.to_lowercase().replace('-', "_").as_str()).map_err(|_| format!("unknown colour {}", name))
+}